@@ -5,6 +5,36 @@ import struct
 HOST = "localhost"
 PORT = 35326
 
+PROT_MAGIC = b"OTHELLRS"
+PROT_VERSION = 4
+
+MSG_SEARCH = 0x01
+MSG_PING = 0x02
+MSG_CLOSE = 0x03
+MSG_ANALYZE = 0x04
+MSG_SEARCH_TIMED = 0x05
+MSG_SEARCH_REPLY = 0x81
+MSG_PONG = 0x82
+MSG_ANALYZE_REPLY = 0x84
+MSG_ERROR = 0xFF
+
+PARAM_PV = 1 << 14
+
+LEGACY_ERROR_MOVE = 0xFF
+
+STATUS_NO_MODEL = 0x01
+STATUS_NO_BOOK = 0x02
+
+
+def recv_exact(s, n):
+    buf = b''
+    while len(buf) < n:
+        chunk = s.recv(n - len(buf))
+        if not chunk:
+            raise ConnectionError("server closed the connection")
+        buf += chunk
+    return buf
+
 
 def evaluate_position(host, port, me, enemy, time, params):
     
@@ -17,15 +47,149 @@ def evaluate_position(host, port, me, enemy, time, params):
         s.sendall(struct.pack('!QQHH', me, enemy, time, params))
         
         # read response
-        res_buf = b''
-        while len(res_buf) < 3:
-            res_buf += s.recv(3)
+        res_buf = recv_exact(s, 3)
+        
+        # the server couldn't handle the request, the eval is the error code
+        if res_buf[0] == LEGACY_ERROR_MOVE:
+            raise ValueError(f"server returned error code {struct.unpack('!h', res_buf[1:])[0]}")
+        
+        # if the P bit is set, the principal variation follows
+        if params & PARAM_PV:
+            pv_len = recv_exact(s, 1)[0]
+            return decode_reply(res_buf + bytes([pv_len]) + recv_exact(s, pv_len))
         
         # return unpacked data (best_move, centidisk_eval)
         return struct.unpack('!Bh', res_buf)
 
 
-def serialize_params(*, adj_time, use_book, solve_end_exact, solve_end_adaptive, mid_depth, end_depth):
+SCORE_KINDS = ['heuristic', 'exact', 'wld', 'bound', 'selective']
+SCORE_SELECTIVE = 4
+
+
+def reply_head_len(reply, with_kind):
+    """
+    Length of the !Bh head of a reply, with the score kind from v3 and the confidence byte of a selective score
+    """
+    if not with_kind:
+        return 3
+    return 5 if reply[3] == SCORE_SELECTIVE else 4
+
+
+def decode_reply(reply, with_kind=False):
+    """
+    Decodes a search reply, returns (best_move, centidisk_eval) or (best_move, centidisk_eval, pv) for extended replies
+    If with_kind (protocol v3), the score kind follows the eval: (best_move, centidisk_eval, kind[, pv])
+    A selective kind includes its confidence, like 'selective 95%'
+    """
+    best_move, q = struct.unpack('!Bh', reply[:3])
+    head_len = reply_head_len(reply, with_kind)
+    if not with_kind:
+        head = (best_move, q)
+    elif reply[3] == SCORE_SELECTIVE:
+        head = (best_move, q, f'{SCORE_KINDS[reply[3]]} {reply[4]}%')
+    else:
+        head = (best_move, q, SCORE_KINDS[reply[3]])
+    # the pv length follows the head
+    if len(reply) == head_len:
+        return head
+    pv_len = reply[head_len]
+    return head + (list(reply[head_len + 1:head_len + 1 + pv_len]),)
+
+
+def status_names(status):
+    """
+    Names of the bits of an engine status, an empty list if the engine isn't degraded
+    """
+    return [name for bit, name in ((STATUS_NO_MODEL, 'no model'), (STATUS_NO_BOOK, 'no book')) if status & bit]
+
+
+class EngineConnection:
+    """
+    Persistent connection using protocol v2, many requests can be sent over one connection
+    """
+    
+    def __init__(self, host, port):
+        self.s = socket.create_connection((host, port))
+        self.next_id = 0
+        
+        # handshake
+        self.s.sendall(PROT_MAGIC + struct.pack('!H', PROT_VERSION))
+        magic = recv_exact(self.s, len(PROT_MAGIC))
+        version, = struct.unpack('!H', recv_exact(self.s, 2))
+        if magic != PROT_MAGIC or version < 2:
+            raise ConnectionError(f"server doesn't support protocol v2 (version={version})")
+        # v3 servers label each score as heuristic, exact, wld, bound or selective
+        self.with_kind = version >= 3
+        # v4 servers send their status with every reply, see status_names
+        self.with_status = version >= 4
+        self.status = 0
+    
+    def take_status(self, reply, offset):
+        """
+        Removes the engine status at offset from a v4 reply and keeps it in self.status
+        """
+        if not self.with_status:
+            return reply
+        self.status = reply[offset]
+        return reply[:offset] + reply[offset + 1:]
+    
+    def request(self, msg_type, payload=b''):
+        self.next_id += 1
+        self.s.sendall(struct.pack('!IIB', 4 + 1 + len(payload), self.next_id, msg_type) + payload)
+        
+        length, request_id, reply_type = struct.unpack('!IIB', recv_exact(self.s, 9))
+        reply = recv_exact(self.s, length - 4 - 1)
+        assert request_id == self.next_id, "reply for a different request"
+        if reply_type == MSG_ERROR:
+            raise ValueError(f"server returned error code {reply[0]}")
+        return reply_type, reply
+    
+    def evaluate_position(self, me, enemy, time, params):
+        _, reply = self.request(MSG_SEARCH, struct.pack('!QQHH', me, enemy, time, params))
+        reply = self.take_status(reply, reply_head_len(reply, self.with_kind))
+        # (best_move, centidisk_eval[, kind][, pv])
+        return decode_reply(reply, self.with_kind)
+    
+    def evaluate_position_timed(self, me, enemy, time, params, move_time_ms):
+        """
+        Searches the midgame as deep as possible (up to the depth in params) within move_time_ms
+        """
+        _, reply = self.request(MSG_SEARCH_TIMED, struct.pack('!QQHHI', me, enemy, time, params, move_time_ms))
+        reply = self.take_status(reply, reply_head_len(reply, self.with_kind))
+        return decode_reply(reply, self.with_kind)
+    
+    def analyze_position(self, me, enemy, params, n=0):
+        """
+        Scores the best n moves (all if n = 0), returns a list of (move, centidisk_eval[, kind], pv) best first
+        """
+        _, reply = self.request(MSG_ANALYZE, struct.pack('!QQHHB', me, enemy, 0, params, n))
+        reply = self.take_status(reply, 1)
+        moves = []
+        offset = 1
+        for _ in range(reply[0]):
+            head_len = reply_head_len(reply[offset:], self.with_kind)
+            pv_len = reply[offset + head_len]
+            moves.append(decode_reply(reply[offset:offset + head_len + 1 + pv_len], self.with_kind))
+            offset += head_len + 1 + pv_len
+        return moves
+    
+    def ping(self):
+        reply_type, reply = self.request(MSG_PING)
+        self.take_status(reply, 0)
+        return reply_type == MSG_PONG
+    
+    def close(self):
+        self.s.sendall(struct.pack('!IIB', 4 + 1, 0, MSG_CLOSE))
+        self.s.close()
+    
+    def __enter__(self):
+        return self
+    
+    def __exit__(self, *args):
+        self.close()
+
+
+def serialize_params(*, adj_time, use_book, solve_end_exact, solve_end_adaptive, mid_depth, end_depth, pv=False, sym_average=False):
     assert mid_depth in range(0, 11), "invalid range for mid_depth"
     assert end_depth in range(0, 23), "invalid range for end_depth"
     p = 0
@@ -35,6 +199,8 @@ def serialize_params(*, adj_time, use_book, solve_end_exact, solve_end_adaptive,
     p |= int(solve_end_exact) << 11
     p |= int(use_book) << 12
     p |= int(adj_time) << 13
+    p |= int(pv) << 14
+    p |= int(sym_average) << 15
     return p
 
 
@@ -48,6 +214,10 @@ if __name__ == '__main__':
         solve_end_exact=True,
         solve_end_adaptive=False,
         mid_depth=5,
-        end_depth=20
+        end_depth=20,
+        pv=True
     )
     print(evaluate_position(HOST, PORT, black, white, time, params))
+    with EngineConnection(HOST, PORT) as conn:
+        print(conn.evaluate_position(black, white, time, params))
+        print(conn.evaluate_position(white, black, time, params))
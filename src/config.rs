@@ -0,0 +1,381 @@
+use std::fs;
+use crate::endgame::Confidence;
+
+/// Usage printed for --help and bad arguments
+pub const USAGE: &str = "Usage: othello_rs [port] [options]
+
+Options:
+  --config <file>         read settings from a config file (key = value per line)
+  --model <path>          path to the torchscript model (default data/model.pt)
+  --evaluator <name>      midgame eval, nn (the neural network), pattern (endgame pattern weights),
+                          mobility (disks and mobility), classic (mobility, frontier, corners and patterns)
+                          or random (default nn, classic if the network can't be loaded)
+  --eval-seed <n>         seed of the random evaluator (default 0)
+  --backend <name>        neural network inference, torch (libtorch), native (Rust, no libtorch)
+                          or quantized (native with int8 weights) (default torch)
+  --weights <path>        path to the weights for the native backend (default data/model.bin)
+  --book <path>           path to the opening book (default data/book.dat)
+  --no-book               run without an opening book
+  --bind <address>        address to listen on (default 0.0.0.0)
+  --port <port>           port to listen on (default 35326)
+  --workers <n>           number of searches run at once, for any number of clients (default 8)
+  --idle-timeout <n>      seconds a v2 connection can wait between requests before it is closed (default 0, no limit)
+  --model-replicas <n>    number of model copies, midgame searches that can run at once (default one per worker)
+  --batch-latency-us <n>  batch the evals of all searches on one model, waiting up to n microseconds (default 0, no batching)
+  --max-batch <n>         max number of boards in a batch (default 4096)
+  --tt-mb <n>             megabytes for the midgame transposition table (default 64)
+  --eg-tt-mb <n>          megabytes for the endgame transposition table (default 64)
+  --eval-cache-mb <n>     megabytes for the cache of network evals (default 32, 0 for none)
+  --sym-evals <n>         symmetries evals are averaged over in searches with the Y bit, 1, 2, 4 or 8 (default 8)
+  --eg-threads <n>        threads used by each endgame solve (default 1)
+  --eg-max-nodes <n>      abort endgame solves after n nodes and use the midgame search (default 0, no limit)
+  --eg-max-ms <n>         abort endgame solves after n milliseconds and use the midgame search (default 60000, 0 for no limit)
+  --eg-selective-empties <n>  solve deeper endgames up to n empties selectively (default 0, off)
+  --eg-confidence <n>     max confidence of selective solves in %, 73, 87, 95 or 98 (default 95)
+  --torch-threads <n>     libtorch intra-op threads (default set by libtorch)
+  --max-mid-depth <n>     max midgame depth a client can request, 1-31 (default 10)
+  --max-end-depth <n>     max endgame depth a client can request, 1-31 (default 22)
+  --nboard                run as an NBoard engine over stdin/stdout
+  --quant-report          print the error of the quantized network against the native one and exit
+  --help                  print this message
+
+Config file keys are the option names without the dashes, with _ in place of -.
+Ex: max_mid_depth = 8
+Options given on the command line override the config file.";
+
+/// How the neural network is evaluated
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+	/// the torchscript model in libtorch
+	#[cfg(feature = "torch")]
+	Torch,
+	/// weights exported by training/export_weights.py, evaluated in Rust
+	Native,
+	/// the native weights quantized to int8 at load time
+	Quantized
+}
+
+/// Which eval the midgame search uses
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EvaluatorKind {
+	/// the neural network, evaluated by the backend
+	NN,
+	/// the pattern weights of the endgame solver
+	Pattern,
+	/// disk difference and mobility
+	DiskMobility,
+	/// mobility, frontier and corners blended into the pattern weights
+	Classic,
+	/// a random eval per position, seeded with eval_seed
+	Random
+}
+
+/// Max depths a client can request, deeper requests are clamped
+#[derive(Clone, Copy)]
+pub struct DepthLimits {
+	pub max_mid_depth: u8,
+	pub max_end_depth: u8
+}
+
+/// Settings of the engine, from the command line and config file
+pub struct EngineConfig {
+	/// path to the torchscript model
+	pub model_path: String,
+	pub evaluator: EvaluatorKind,
+	/// seed of the random evaluator
+	pub eval_seed: u64,
+	pub backend: Backend,
+	/// path to the weights for the native backend
+	pub weights_path: String,
+	/// path to the opening book, None runs without a book
+	pub book_path: Option<String>,
+	/// address the server listens on
+	pub bind: String,
+	pub port: u16,
+	/// number of searches run at once, each connection has its own thread
+	pub workers: usize,
+	/// max seconds between the requests of a v2 connection, None for no limit
+	pub idle_timeout: Option<u64>,
+	/// number of model copies, None for one per worker
+	pub model_replicas: Option<usize>,
+	/// max wait for more evals to batch together, 0 gives each search its own model
+	pub batch_latency_us: u64,
+	/// max number of boards in a batch
+	pub max_batch: usize,
+	/// size of the midgame transposition table in MB
+	pub tt_mb: usize,
+	/// size of the endgame transposition table in MB
+	pub eg_tt_mb: usize,
+	/// size of the eval cache in MB
+	pub eval_cache_mb: usize,
+	/// number of symmetries evals are averaged over in searches that ask for it
+	pub sym_evals: usize,
+	/// threads used by each endgame solve
+	pub eg_threads: usize,
+	/// node and time limits of an endgame solve, None for no limit
+	pub eg_max_nodes: Option<u64>,
+	pub eg_max_ms: Option<u64>,
+	/// endgames with more empties than the requested depth and up to this many are solved selectively, 0 for none
+	pub eg_selective_empties: u8,
+	/// max confidence level of a selective solve
+	pub eg_confidence: Confidence,
+	/// libtorch intra-op threads, None leaves the libtorch default
+	pub torch_threads: Option<i32>,
+	pub depth_limits: DepthLimits,
+	/// run as an NBoard engine instead of a server
+	pub nboard: bool,
+	/// compare the quantized network to the native one instead of running
+	pub quant_report: bool,
+	/// print the usage and exit
+	pub help: bool
+}
+
+impl Default for EngineConfig {
+	fn default() -> Self {
+		EngineConfig {
+			model_path: "data/model.pt".to_string(),
+			evaluator: EvaluatorKind::NN,
+			eval_seed: 0,
+			#[cfg(feature = "torch")]
+			backend: Backend::Torch,
+			#[cfg(not(feature = "torch"))]
+			backend: Backend::Native,
+			weights_path: "data/model.bin".to_string(),
+			book_path: Some("data/book.dat".to_string()),
+			bind: "0.0.0.0".to_string(),
+			port: 35326,
+			workers: 8,
+			idle_timeout: None,
+			model_replicas: None,
+			batch_latency_us: 0,
+			max_batch: 4096,
+			tt_mb: 64,
+			eg_tt_mb: 64,
+			eval_cache_mb: 32,
+			sym_evals: 8,
+			eg_threads: 1,
+			eg_max_nodes: None,
+			eg_max_ms: Some(60000),
+			eg_selective_empties: 0,
+			eg_confidence: Confidence::P95,
+			torch_threads: None,
+			depth_limits: DepthLimits {
+				max_mid_depth: 10,
+				max_end_depth: 22
+			},
+			nboard: false,
+			quant_report: false,
+			help: false
+		}
+	}
+}
+
+/// Parses a setting value, naming the setting in the error
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> {
+	value.parse().map_err(|_| format!("invalid value for {}: {}", key, value))
+}
+
+/// Parses a depth limit, which has to fit in the 5 bits of a request
+fn parse_depth(key: &str, value: &str) -> Result<u8, String> {
+	match parse_value::<u8>(key, value)? {
+		depth @ 1..=31 => Ok(depth),
+		_ => Err(format!("{} must be between 1 and 31: {}", key, value))
+	}
+}
+
+/// Parses a limit, where 0 is no limit
+fn parse_limit(key: &str, value: &str) -> Result<Option<u64>, String> {
+	match parse_value(key, value)? {
+		0 => Ok(None),
+		limit => Ok(Some(limit))
+	}
+}
+
+/// Parses a flag from a config file, a flag given on the command line is always true
+fn parse_flag(key: &str, value: &str) -> Result<bool, String> {
+	match value {
+		"true" | "yes" | "1" => Ok(true),
+		"false" | "no" | "0" => Ok(false),
+		_ => Err(format!("invalid value for {}: {}", key, value))
+	}
+}
+
+/// Settings that don't take a value on the command line
+fn is_flag(key: &str) -> bool {
+	matches!(key, "no_book" | "nboard" | "quant_report" | "help")
+}
+
+impl EngineConfig {
+	
+	/// Number of model copies to load
+	pub fn num_model_replicas(&self) -> usize {
+		self.model_replicas.unwrap_or(self.workers)
+	}
+	
+	/// Builds the config from the command line arguments (without the program name)
+	/// Settings are applied in order: defaults, then the config file, then the other arguments
+	pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+		
+		// collect (key, value) pairs, a positional argument is the port
+		let mut settings = vec![];
+		while let Some(arg) = args.next() {
+			match arg.strip_prefix("--") {
+				Some(name) => {
+					let key = name.replace('-', "_");
+					let value = if is_flag(&key) {
+						"true".to_string()
+					} else {
+						args.next().ok_or(format!("missing value for --{}", name))?
+					};
+					settings.push((key, value));
+				}
+				None => settings.push(("port".to_string(), arg))
+			}
+		}
+		
+		let mut config = EngineConfig::default();
+		
+		if let Some((_, path)) = settings.iter().find(|(key, _)| key == "config") {
+			config.apply_file(path)?;
+		}
+		
+		for (key, value) in settings.iter() {
+			if key != "config" {
+				config.set(key, value)?;
+			}
+		}
+		
+		Ok(config)
+		
+	}
+	
+	/// Applies a config file of key = value lines
+	/// Empty lines and lines starting with # are ignored
+	fn apply_file(&mut self, path: &str) -> Result<(), String> {
+		
+		let contents = fs::read_to_string(path)
+			.map_err(|e| format!("failed to read config file {}: {}", path, e))?;
+		
+		for (i, line) in contents.lines().enumerate() {
+			
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			
+			let (key, value) = line.split_once('=')
+				.ok_or(format!("{}:{}: expected key = value", path, i + 1))?;
+			
+			self.set(key.trim(), value.trim())
+				.map_err(|e| format!("{}:{}: {}", path, i + 1, e))?;
+			
+		}
+		
+		Ok(())
+		
+	}
+	
+	/// Sets a single setting by its config file key
+	fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+		
+		match key {
+			"model" => self.model_path = value.to_string(),
+			"evaluator" => self.evaluator = match value {
+				"nn" => EvaluatorKind::NN,
+				"pattern" => EvaluatorKind::Pattern,
+				"mobility" => EvaluatorKind::DiskMobility,
+				"classic" => EvaluatorKind::Classic,
+				"random" => EvaluatorKind::Random,
+				_ => return Err(format!("unknown evaluator: {}", value))
+			},
+			"eval_seed" => self.eval_seed = parse_value(key, value)?,
+			"backend" => self.backend = match value {
+				#[cfg(feature = "torch")]
+				"torch" => Backend::Torch,
+				"native" => Backend::Native,
+				"quantized" => Backend::Quantized,
+				_ => return Err(format!("unknown backend: {}", value))
+			},
+			"weights" => self.weights_path = value.to_string(),
+			"book" => self.book_path = Some(value.to_string()),
+			"no_book" => if parse_flag(key, value)? { self.book_path = None },
+			"bind" => self.bind = value.to_string(),
+			"port" => self.port = parse_value(key, value)?,
+			"workers" => match parse_value(key, value)? {
+				0 => return Err("workers must be at least 1".to_string()),
+				workers => self.workers = workers
+			},
+			"idle_timeout" => self.idle_timeout = parse_limit(key, value)?,
+			"model_replicas" => match parse_value(key, value)? {
+				0 => return Err("model_replicas must be at least 1".to_string()),
+				replicas => self.model_replicas = Some(replicas)
+			},
+			"batch_latency_us" => self.batch_latency_us = parse_value(key, value)?,
+			"max_batch" => match parse_value(key, value)? {
+				0 => return Err("max_batch must be at least 1".to_string()),
+				max_batch => self.max_batch = max_batch
+			},
+			"tt_mb" => self.tt_mb = parse_value(key, value)?,
+			"eg_tt_mb" => self.eg_tt_mb = parse_value(key, value)?,
+			"eval_cache_mb" => self.eval_cache_mb = parse_value(key, value)?,
+			"sym_evals" => match parse_value(key, value)? {
+				symmetries @ (1 | 2 | 4 | 8) => self.sym_evals = symmetries,
+				_ => return Err(format!("sym_evals must be 1, 2, 4 or 8: {}", value))
+			},
+			"eg_max_nodes" => self.eg_max_nodes = parse_limit(key, value)?,
+			"eg_max_ms" => self.eg_max_ms = parse_limit(key, value)?,
+			"eg_selective_empties" => self.eg_selective_empties = parse_value(key, value)?,
+			"eg_confidence" => match Confidence::from_percent(parse_value(key, value)?) {
+				Some(confidence) => self.eg_confidence = confidence,
+				None => return Err(format!("eg_confidence must be 73, 87, 95 or 98: {}", value))
+			},
+			"eg_threads" => match parse_value(key, value)? {
+				0 => return Err("eg_threads must be at least 1".to_string()),
+				threads => self.eg_threads = threads
+			},
+			"torch_threads" => match parse_value(key, value)? {
+				threads if threads < 1 => return Err("torch_threads must be at least 1".to_string()),
+				threads => self.torch_threads = Some(threads)
+			},
+			"max_mid_depth" => self.depth_limits.max_mid_depth = parse_depth(key, value)?,
+			"max_end_depth" => self.depth_limits.max_end_depth = parse_depth(key, value)?,
+			"nboard" => self.nboard = parse_flag(key, value)?,
+			"quant_report" => self.quant_report = parse_flag(key, value)?,
+			"help" => self.help = parse_flag(key, value)?,
+			_ => return Err(format!("unknown setting: {}", key))
+		}
+		
+		Ok(())
+		
+	}
+	
+}
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	
+	fn parse(args: &[&str]) -> Result<EngineConfig, String> {
+		EngineConfig::from_args(args.iter().map(|arg| arg.to_string()))
+	}
+	
+	#[test]
+	fn quant_report_is_a_flag() {
+		
+		let config = parse(&["--quant-report"]).unwrap();
+		assert!(config.quant_report);
+		
+		// the next argument is still parsed on its own
+		let config = parse(&["--quant-report", "35327"]).unwrap();
+		assert!(config.quant_report);
+		assert_eq!(config.port, 35327);
+		
+	}
+	
+	#[test]
+	fn options_need_a_value() {
+		assert_eq!(parse(&["--workers"]).err().unwrap(), "missing value for --workers");
+	}
+	
+}
@@ -1,4 +1,38 @@
-use crate::othello_board::{empty_disks, evaluation, game_over, generate_moves, make_move, next_bit_move, to_bit_move_vec, to_idx_move_vec};
+use crate::othello_board::{empty_disks, evaluation, game_over, generate_moves, make_move, next_bit_move, stable_disks, to_idx_move_vec};
+use crate::search_result::{PvLine, ScoreKind, SearchResult};
+use crate::transposition_table::{hash_position, ClientTable, EgEntry, EG_PROVEN};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+
+/// Empties at which the solver below the root stops using the strong move ordering
+const OPTIMAL_STOP_MO_AT_EMPTIES: u8 = 8;
+
+/// Empties at which the solver stops generating moves and tries each empty square
+const LAST_EMPTIES: u8 = 4;
+
+/// The 4 quadrants of the board, for parity move ordering
+const QUADRANTS: [u64; 4] = [0x000000000F0F0F0F, 0x00000000F0F0F0F0, 0x0F0F0F0F00000000, 0xF0F0F0F000000000];
+
+/// Min empties for a position to use the transposition table
+/// Closer to the end, solving a position again is cheaper than the lookup
+const EG_TT_MIN_EMPTIES: u8 = 8;
+
+/// Min empties for a node of a selective solve to try a ProbCut
+const PROBCUT_MIN_EMPTIES: u8 = 12;
+
+/// Depth of the heuristic_eg_search that predicts the score for a ProbCut
+const PROBCUT_DEPTH: u8 = 2;
+
+/// Standard deviation of the exact score around the PROBCUT_DEPTH search, in centidisks
+/// Measured by the probcut_error test (run with --ignored) on random games at 12-16 empties
+const PROBCUT_SIGMA: f64 = 1340.0;
+
+/// Mean of the exact score minus the PROBCUT_DEPTH search, in centidisks, measured with PROBCUT_SIGMA
+/// Positive as the shallow search underestimates the score, the predicted score is the shallow score plus the bias
+const PROBCUT_BIAS: i32 = 270;
 
 /// End game heuristic weights
 /// Generated by thor database games & gradient descent
@@ -86,10 +120,10 @@ fn flip_diag_a1h8(mut x: u64) -> u64 {
 /// Positions with 7-25 empties are within the expected range for this function.
 /// MAE=937 centidisks
 /// Returns from POV of `me` and is for use in a negamax framework.
-fn heuristic_eg_nega(me: u64, enemy: u64) -> i32 {
+pub fn heuristic_eg_nega(me: u64, enemy: u64) -> i32 {
 	
 	// TODO: use more applicable regions and use asm pext instruction to index into weights after CPU upgrade
-	// https://docs.rs/bitintr/0.3.0/x86_64-pc-windows-msvc/bitintr/trait.Pext.html#tymethod.pext
+	// std::arch::x86_64::_pext_u64, as in make_move_bmi2
 	// Zen2 arch takes 250 cycles for pext. Zen3 takes 1 cycle
 	// https://www.anandtech.com/show/16214/amd-zen-3-ryzen-deep-dive-review-5950x-5900x-5800x-and-5700x-tested/6
 	
@@ -165,16 +199,389 @@ fn heuristic_eg_nega_d1(me: u64, enemy: u64) -> i32 {
 	
 }
 
+/// Fail-soft negamax `depth` deep on `heuristic_eg_nega`, game over positions get their exact score
+fn heuristic_eg_search(me: u64, enemy: u64, depth: u8, mut alpha: i32, beta: i32) -> i32 {
+	
+	if depth == 0 {
+		return heuristic_eg_nega(me, enemy);
+	}
+	
+	let mut moves = generate_moves(me, enemy);
+	
+	// if no moves, pass
+	if moves == 0 {
+		if generate_moves(enemy, me) == 0 {
+			return 100 * evaluation(me, enemy) as i32;
+		}
+		return -heuristic_eg_search(enemy, me, depth, -beta, -alpha);
+	}
+	
+	let mut best_score = i32::MIN + 1;
+	
+	// for each move
+	while moves != 0 {
+		
+		let mov = next_bit_move(&mut moves);
+		
+		let (me, enemy) = make_move(mov, me, enemy);
+		let q = -heuristic_eg_search(enemy, me, depth - 1, -beta, -alpha);
+		
+		if q >= beta {
+			return q;
+		}
+		if q > best_score {
+			best_score = q;
+			alpha = alpha.max(q);
+		}
+		
+	}
+	
+	best_score
+	
+}
+
+/// Node budget and deadline of a solve
+/// Once either is hit, the solve unwinds and reports that it was aborted
+#[derive(Clone, Copy)]
+pub struct EgLimits {
+	pub max_nodes: Option<u64>,
+	pub deadline: Option<Instant>
+}
+
+/// Nodes searched by all threads of a solve, and whether it hit its limits
+struct EgBudget {
+	limits: EgLimits,
+	nodes: AtomicU64,
+	exceeded: AtomicBool
+}
+
+impl EgBudget {
+	
+	fn new(limits: EgLimits) -> Self {
+		EgBudget {
+			limits,
+			nodes: AtomicU64::new(0),
+			exceeded: AtomicBool::new(false)
+		}
+	}
+	
+	fn exceeded(&self) -> bool {
+		self.exceeded.load(Ordering::Relaxed)
+	}
+	
+}
+
+/// Confidence levels of the selective solver
+/// A ProbCut is made when the shallow search is this likely to be right about the side of the window
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Confidence {
+	P73,
+	P87,
+	P95,
+	P98
+}
+
+impl Confidence {
+	
+	pub const ALL: [Confidence; 4] = [Confidence::P73, Confidence::P87, Confidence::P95, Confidence::P98];
+	
+	/// Gets the level for a percentage, which must be one of 73, 87, 95 or 98
+	pub fn from_percent(percent: u8) -> Option<Confidence> {
+		Confidence::ALL.into_iter().find(|level| level.percent() == percent)
+	}
+	
+	pub fn percent(self) -> u8 {
+		match self {
+			Confidence::P73 => 73,
+			Confidence::P87 => 87,
+			Confidence::P95 => 95,
+			Confidence::P98 => 98
+		}
+	}
+	
+	/// Number of standard deviations the prediction must be outside of the window
+	fn t(self) -> f64 {
+		match self {
+			Confidence::P73 => 1.1,
+			Confidence::P87 => 1.5,
+			Confidence::P95 => 2.0,
+			Confidence::P98 => 2.6
+		}
+	}
+	
+}
+
+/// State shared by all searches of a solve
+struct EgSolver<'a> {
+	tt: ClientTable<'a>,
+	/// threads used by each search
+	threads: usize,
+	/// the nodes and time of all searches count towards the same limits
+	budget: EgBudget,
+	/// None for an exact solve
+	selectivity: Option<Confidence>
+}
+
+impl<'a> EgSolver<'a> {
+	
+	fn new(tt: ClientTable<'a>, threads: usize, limits: EgLimits, selectivity: Option<Confidence>) -> Self {
+		EgSolver {
+			tt,
+			threads,
+			budget: EgBudget::new(limits),
+			selectivity
+		}
+	}
+	
+}
+
+/// Nodes a thread searches between checks of the node budget and the deadline
+const LIMIT_CHECK_NODES: u64 = 1024;
+
+/// State shared by all nodes of a single solve
+struct EgContext<'a> {
+	tt: ClientTable<'a>,
+	/// index of the root move being solved
+	root_index: usize,
+	/// index of the first root move that failed high, the solves of later moves are aborted
+	cutoff: &'a AtomicUsize,
+	budget: &'a EgBudget,
+	/// nodes searched by this thread that aren't in the budget yet
+	nodes: Cell<u64>,
+	/// ProbCut margin in centidisks, None for an exact solve
+	probcut_margin: Option<i32>,
+	/// stored bounds are only used if they have at least this confidence, see EgEntry
+	confidence: u8
+}
+
+impl<'a> EgContext<'a> {
+	
+	fn new(solver: &'a EgSolver, root_index: usize, cutoff: &'a AtomicUsize) -> Self {
+		EgContext {
+			tt: solver.tt,
+			root_index,
+			cutoff,
+			budget: &solver.budget,
+			nodes: Cell::new(0),
+			probcut_margin: solver.selectivity.map(|level| (level.t() * PROBCUT_SIGMA) as i32),
+			confidence: solver.selectivity.map_or(EG_PROVEN, |level| level as u8)
+		}
+	}
+	
+	#[inline(always)]
+	fn count_node(&self) {
+		self.nodes.set(self.nodes.get() + 1);
+	}
+	
+	/// True if the result of this solve isn't needed anymore, it must then be thrown away
+	#[inline(always)]
+	fn aborted(&self) -> bool {
+		self.cutoff.load(Ordering::Relaxed) < self.root_index || self.budget.exceeded()
+	}
+	
+	/// Adds the nodes of this thread to the budget, returns the nodes of all threads so far
+	fn flush_nodes(&self) -> u64 {
+		let nodes = self.nodes.replace(0);
+		self.budget.nodes.fetch_add(nodes, Ordering::Relaxed) + nodes
+	}
+	
+	/// Checks the limits, returns true if the solve should stop
+	/// The shared node count and the clock are only touched every LIMIT_CHECK_NODES nodes of this thread,
+	/// so a solve can run over max_nodes by that many nodes per thread
+	#[inline(always)]
+	fn check_limits(&self) -> bool {
+		
+		if self.aborted() {
+			return true;
+		}
+		
+		if self.nodes.get() < LIMIT_CHECK_NODES {
+			return false;
+		}
+		
+		let limits = &self.budget.limits;
+		let nodes = self.flush_nodes();
+		let over_nodes = matches!(limits.max_nodes, Some(max_nodes) if nodes > max_nodes);
+		let over_time = matches!(limits.deadline, Some(deadline) if Instant::now() >= deadline);
+		
+		if over_nodes || over_time {
+			self.budget.exceeded.store(true, Ordering::Relaxed);
+			return true;
+		}
+		
+		false
+		
+	}
+	
+}
+
+impl Drop for EgContext<'_> {
+	// the budget of a selective solve carries over to the next level, so it needs every node
+	fn drop(&mut self) {
+		self.flush_nodes();
+	}
+}
+
+/// Root moves solved so far, shared by the threads of a solve
+struct RootResults {
+	/// moves that can be in the top n, as (root index, result), best first
+	top: Vec<(usize, SearchResult)>,
+	/// the first move in move order that failed low, with the highest upper bound of all of them,
+	/// returned if every move fails low
+	best_low: Option<(usize, SearchResult)>
+}
+
+/// A solve of the root moves, shared by its threads
+struct RootSolve<'a> {
+	solver: &'a EgSolver<'a>,
+	/// (applied move, me, enemy) of each root move, in move order
+	states: &'a [(u8, u64, u64)],
+	alpha: i8,
+	beta: i8,
+	/// number of best moves to solve exactly
+	n: usize,
+	results: Mutex<RootResults>,
+	/// next root move that no thread has taken
+	next_move: AtomicUsize,
+	/// index of the first root move that failed high
+	cutoff: AtomicUsize
+}
+
 /// Solves the endgame.
-/// Fail-hard negamax
-/// Returns (move, eval)
-pub fn solve_endgame_root(me: u64, enemy: u64, mut alpha: i8, beta: i8) -> (u8, i8) {
+/// Fail-soft negamax below the root, fail-hard at the root: a score outside of (alpha, beta) is returned as alpha or beta
+/// The root moves are split between `threads` threads, which gives the same move, score and kind as a single thread:
+/// the exact score with the first best move in move order, beta with the first move that fails high,
+/// or alpha with the first move if every move fails low. Only the principal variation can differ,
+/// it ends early where a thread found the position solved in the table
+/// Returns the best move, disk eval and the principal variation, or None if the solve hit its limits
+pub fn solve_endgame_root(tt: ClientTable, me: u64, enemy: u64, alpha: i8, beta: i8, threads: usize, limits: EgLimits) -> Option<SearchResult> {
+	
+	tt.new_search();
 	
-	const OPTIMAL_STOP_MO_AT_EMPTIES: u8 = 8;
+	// a fail-soft bound depends on which positions the threads found in the table
+	let result = solve_endgame_root_window(&EgSolver::new(tt, threads, limits, None), me, enemy, alpha, beta)?;
+	Some(SearchResult { score: result.score.clamp(alpha as i32, beta as i32), ..result })
+	
+}
+
+/// Solves win/loss/draw first with a null window, then the exact score with MTD(f) if `exact`
+/// The proven winner comes back quickly, the margin is only searched for afterwards.
+/// If the limits are hit while searching the margin, the best proven result is returned (Wld or Bound).
+/// Returns None if the limits are hit before the winner is known
+/// https://www.chessprogramming.org/MTD(f)
+pub fn solve_endgame_wld_first(tt: ClientTable, me: u64, enemy: u64, exact: bool, threads: usize, limits: EgLimits) -> Option<SearchResult> {
+	tt.new_search();
+	solve_endgame_mtd(&EgSolver::new(tt, threads, limits, None), me, enemy, exact)
+}
+
+/// Solves the endgame selectively, pruning moves with ProbCut (see solve_endgame_mo)
+/// Solves at each confidence level in turn, from the lowest up to `max_confidence`, with a WLD search then MTD(f)
+/// Returns the result of the highest level that finished, with that level as its confidence,
+/// or None if the limits are hit before the lowest level finishes
+/// https://www.chessprogramming.org/ProbCut
+pub fn solve_endgame_selective(tt: ClientTable, me: u64, enemy: u64, max_confidence: Confidence, threads: usize, mut limits: EgLimits) -> Option<SearchResult> {
+	
+	tt.new_search();
+	
+	let mut best = None;
+	
+	for level in Confidence::ALL.into_iter().filter(|level| *level <= max_confidence) {
+		
+		// every level starts with a new budget, so use the same deadline and the nodes that are left
+		let solver = EgSolver::new(tt, threads, limits, Some(level));
+		
+		match solve_endgame_mtd(&solver, me, enemy, true) {
+			Some(result) if result.kind == ScoreKind::Exact => {
+				best = Some(SearchResult { kind: ScoreKind::Selective, confidence: Some(level), ..result });
+			}
+			_ => break
+		}
+		
+		limits = EgLimits { max_nodes: limits.max_nodes.map(|max_nodes| max_nodes.saturating_sub(solver.budget.nodes.load(Ordering::Relaxed))), ..limits };
+		
+	}
+	
+	best
+	
+}
+
+/// Solves win/loss/draw with a null window, then the exact score with MTD(f) if `exact`, see solve_endgame_wld_first
+fn solve_endgame_mtd(solver: &EgSolver, me: u64, enemy: u64, exact: bool) -> Option<SearchResult> {
+	
+	let wld = solve_endgame_root_window(solver, me, enemy, -1, 1)?;
+	
+	// a draw is exact
+	if wld.kind == ScoreKind::Exact {
+		return Some(wld);
+	}
+	if !exact {
+		return Some(SearchResult { score: wld.score.signum(), kind: ScoreKind::Wld, ..wld });
+	}
+	
+	// fail-soft bounds on the score, the wld search already proved one side
+	let (mut lower, mut upper) = if wld.score > 0 { (wld.score, 64) } else { (-64, wld.score) };
+	
+	// the last result to fail high has a move that reaches the lower bound
+	let mut best = wld;
+	let mut guess = best.score;
+	
+	// null window searches until the bounds meet
+	while lower < upper {
+		
+		let beta = if guess == lower { guess + 1 } else { guess };
+		
+		let probe = match solve_endgame_root_window(solver, me, enemy, beta as i8 - 1, beta as i8) {
+			Some(probe) => probe,
+			None => {
+				// out of time, return how much of the margin is proven
+				let bound = if best.score > 0 { lower } else { upper };
+				let kind = if bound.abs() > 1 { ScoreKind::Bound } else { ScoreKind::Wld };
+				return Some(SearchResult { score: bound, kind, ..best });
+			}
+		};
+		
+		guess = probe.score;
+		if guess < beta {
+			upper = guess;
+		} else {
+			lower = guess;
+			best = probe;
+		}
+		
+	}
+	
+	Some(SearchResult { score: lower, kind: ScoreKind::Exact, ..best })
+	
+}
+
+/// Solves every root move exactly, or only the best `n` moves if n > 0
+/// Each move is searched with a window above the n-th best score found so far,
+/// so every returned move has an exact disk difference. A move that fails low can't be in the top n.
+/// Returns one result per move (starting with that move), best first, or None if the solve hit its limits
+pub fn solve_endgame_root_multipv(tt: ClientTable, me: u64, enemy: u64, n: usize, threads: usize, limits: EgLimits) -> Option<Vec<SearchResult>> {
+	
+	let moves = generate_moves(me, enemy);
+	
+	// with no choice of move, this is a normal solve
+	if moves == 0 {
+		return solve_endgame_root(tt, me, enemy, -64, 64, threads, limits).map(|result| vec![result]);
+	}
+	
+	tt.new_search();
+	
+	let states = root_sorted_children(tt, me, enemy, moves);
+	let n = if n == 0 { states.len() } else { n };
+	
+	solve_endgame_root_moves(&EgSolver::new(tt, threads, limits, None), &states, -65, 65, n)
+	
+}
+
+/// Solves the endgame with the window (alpha, beta), see solve_endgame_root
+fn solve_endgame_root_window(solver: &EgSolver, me: u64, enemy: u64, alpha: i8, beta: i8) -> Option<SearchResult> {
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
-		return (65, evaluation(me, enemy));
+		return Some(SearchResult { best_move: 65, score: evaluation(me, enemy) as i32, pv: vec![], kind: ScoreKind::Exact, confidence: None });
 	}
 	
 	// get possible moves
@@ -182,9 +589,151 @@ pub fn solve_endgame_root(me: u64, enemy: u64, mut alpha: i8, beta: i8) -> (u8,
 	
 	// if no moves, pass
 	if moves == 0 {
-		return (65, -solve_endgame_mo(enemy, me, -beta, -alpha, OPTIMAL_STOP_MO_AT_EMPTIES));
+		let cutoff = AtomicUsize::new(usize::MAX);
+		let ctx = EgContext::new(solver, 0, &cutoff);
+		let mut pv = PvLine::new();
+		let mut child_pv = PvLine::new();
+		let q = -solve_endgame_mo(&ctx, enemy, me, -beta, -alpha, OPTIMAL_STOP_MO_AT_EMPTIES, &mut child_pv);
+		if solver.budget.exceeded() {
+			return None;
+		}
+		pv.set(65, &child_pv);
+		return Some(SearchResult { best_move: 65, score: q as i32, pv: pv.moves().to_vec(), kind: window_kind(q, alpha, beta), confidence: None });
+	}
+	
+	// apply each move and sort the child states, best one first
+	let states = root_sorted_children(solver.tt, me, enemy, moves);
+	
+	solve_endgame_root_moves(solver, &states, alpha, beta, 1).map(|mut results| results.remove(0))
+	
+}
+
+/// Kind of a fail-soft score searched with the window (alpha, beta)
+fn window_kind(score: i8, alpha: i8, beta: i8) -> ScoreKind {
+	if score > alpha && score < beta {
+		ScoreKind::Exact
+	} else {
+		ScoreKind::Bound
+	}
+}
+
+/// Solves the root moves and returns the best `n`, best first
+/// Young Brothers Wait at the root: the first move is solved alone to get a bound for the others,
+/// then the threads take the other moves in order, each starting from the best bound found so far.
+/// Moves with the same score keep the move order, as in a solve on a single thread.
+/// Once a move fails high, only that move is returned and the solves of later moves are aborted.
+/// Returns None if the solve hit its limits
+/// https://www.chessprogramming.org/Young_Brothers_Wait_Concept
+fn solve_endgame_root_moves(solver: &EgSolver, states: &[(u8, u64, u64)], alpha: i8, beta: i8, n: usize) -> Option<Vec<SearchResult>> {
+	
+	let solve = RootSolve {
+		solver,
+		states,
+		alpha,
+		beta,
+		n,
+		results: Mutex::new(RootResults { top: Vec::with_capacity(n + 1), best_low: None }),
+		next_move: AtomicUsize::new(1),
+		cutoff: AtomicUsize::new(usize::MAX)
+	};
+	
+	// the eldest brother goes first
+	solve.solve_move(0);
+	
+	// then all threads take the next move until none are left
+	thread::scope(|scope| {
+		for _ in 1..solver.threads {
+			scope.spawn(|| solve.work());
+		}
+		solve.work();
+	});
+	
+	if solver.budget.exceeded() {
+		return None;
+	}
+	
+	let results = solve.results.into_inner().unwrap();
+	if results.top.is_empty() {
+		return Some(vec![results.best_low.unwrap().1]);
+	}
+	
+	Some(results.top.into_iter().map(|(_, result)| result).collect())
+	
+}
+
+impl RootSolve<'_> {
+	
+	/// Solves the next move not taken by another thread, until none are left
+	fn work(&self) {
+		loop {
+			let i = self.next_move.fetch_add(1, Ordering::Relaxed);
+			if i >= self.states.len() || self.cutoff.load(Ordering::Relaxed) < i || self.solver.budget.exceeded() {
+				return;
+			}
+			self.solve_move(i);
+		}
+	}
+	
+	/// Solves the i-th root move and adds it to the results
+	fn solve_move(&self, i: usize) {
+		
+		let (mov, me, enemy) = self.states[i];
+		
+		// only a move better than the n-th best can be in the top n,
+		// a tie is enough if the n-th best comes later in the move order
+		let alpha = {
+			let results = self.results.lock().unwrap();
+			match results.top.get(self.n - 1) {
+				Some((j, nth)) if *j < i => self.alpha.max(nth.score as i8),
+				Some((_, nth)) => self.alpha.max(nth.score as i8 - 1),
+				None => self.alpha
+			}
+		};
+		let beta = self.beta;
+		
+		let ctx = EgContext::new(self.solver, i, &self.cutoff);
+		let mut pv = PvLine::new();
+		let mut child_pv = PvLine::new();
+		
+		let q = -solve_endgame_mo(&ctx, enemy, me, -beta, -alpha, OPTIMAL_STOP_MO_AT_EMPTIES, &mut child_pv);
+		pv.set(mov, &child_pv);
+		let result = SearchResult { best_move: mov, score: q as i32, pv: pv.moves().to_vec(), kind: window_kind(q, alpha, beta), confidence: None };
+		
+		// the cutoff only moves under the lock, so an aborted result is never added
+		let mut results = self.results.lock().unwrap();
+		if ctx.aborted() {
+			return;
+		}
+		
+		// the first move stands for all of them, as its line doesn't reach the bound of another move
+		if q <= alpha {
+			results.best_low = Some(match results.best_low.take() {
+				Some((j, low)) if j < i => (j, SearchResult { score: low.score.max(result.score), ..low }),
+				Some((_, low)) => (i, SearchResult { score: low.score.max(result.score), pv: vec![mov], ..result }),
+				None => (i, SearchResult { pv: vec![mov], ..result })
+			});
+			return;
+		}
+		
+		// a fail-high ends the solve, the moves after it would never have been searched
+		if q >= beta {
+			self.cutoff.fetch_min(i, Ordering::Relaxed);
+			results.top.retain(|(j, _)| *j < i);
+		}
+		
+		results.top.push((i, result));
+		results.top.sort_by_key(|(j, result)| (-result.score, *j));
+		results.top.truncate(self.n);
+		
 	}
 	
+}
+
+/// Applies each root move and sorts the child states, best one first
+/// The best move of a previous solve of this position goes first
+/// Returns (applied move, me, enemy)
+fn root_sorted_children(tt: ClientTable, me: u64, enemy: u64, moves: u64) -> Vec<(u8, u64, u64)> {
+	
 	// apply each move and get the state
 	let mut states: Vec<(u8, u64, u64)> = to_idx_move_vec(moves)
 		.iter()
@@ -197,166 +746,545 @@ pub fn solve_endgame_root(me: u64, enemy: u64, mut alpha: i8, beta: i8) -> (u8,
 	// sort the child states, best one first
 	states.sort_by_cached_key(|(_, me, enemy)| heuristic_eg_nega_d1(*enemy, *me));
 	
-	let mut best_move: u8 = states[0].0;
-	
-	// for each child state
-	for (mov, me, enemy) in states {
-		
-		let q = -solve_endgame_mo(enemy, me, -beta, -alpha, OPTIMAL_STOP_MO_AT_EMPTIES);
-		
-		if q >= beta {
-			return (mov as u8, beta); // fail-hard beta-cutoff
-		}
-		
-		if q > alpha {
-			alpha = q;
-			best_move = mov;
+	if let Some(entry) = tt.probe_eg(hash_position(me, enemy)) {
+		if let Some(i) = states.iter().position(|(mov, _, _)| *mov == entry.best_move) {
+			states[..=i].rotate_right(1);
 		}
-		
 	}
 	
-	return (best_move, alpha);
+	states
 	
 }
 
-/// Fail-hard negamax for endgame solving
+/// Stores a solved position in the table, unless the solve was aborted
+#[inline(always)]
+fn solve_endgame_store(ctx: &EgContext, hash: u64, empties: u8, entry: EgEntry) {
+	if !ctx.aborted() {
+		ctx.tt.store_eg(hash, empties, EgEntry { confidence: ctx.confidence, ..entry });
+	}
+}
+
+/// Fail-soft negamax for endgame solving
 /// Uses move ordering for states with more than stop_mo_at_empties number of empty disks
+/// Positions with at least EG_TT_MIN_EMPTIES empties use the transposition table
 /// Optimal stop_mo_at_empties=7
 /// https://www.chessprogramming.org/Alpha-Beta
-fn solve_endgame_mo(me: u64, enemy: u64, mut alpha: i8, beta: i8, stop_mo_at_empties: u8) -> i8 {
+fn solve_endgame_mo(ctx: &EgContext, me: u64, enemy: u64, mut alpha: i8, mut beta: i8, stop_mo_at_empties: u8, pv: &mut PvLine) -> i8 {
+	
+	// start with an empty line
+	pv.clear();
+	
+	// stop right away if the solve hit its limits or another thread made it useless
+	ctx.count_node();
+	if ctx.check_limits() {
+		return 0;
+	}
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
 		return evaluation(me, enemy);
 	}
 	
+	let empty_disks = empty_disks(me, enemy);
+	let use_tt = empty_disks >= EG_TT_MIN_EMPTIES;
+	let hash = if use_tt { hash_position(me, enemy) } else { 0 };
+	
+	// use the stored bounds to cut off or narrow the window
+	// bounds from a solve with less confidence only give the best move
+	let mut tt_move = 64;
+	if use_tt {
+		if let Some(entry) = ctx.tt.probe_eg(hash) {
+			tt_move = entry.best_move;
+			if entry.confidence >= ctx.confidence {
+				if entry.lower >= beta || entry.lower == entry.upper {
+					pv.set_move(tt_move);
+					return entry.lower;
+				}
+				if entry.upper <= alpha {
+					return entry.upper;
+				}
+				alpha = alpha.max(entry.lower);
+				beta = beta.min(entry.upper);
+			}
+		}
+	}
+	
+	if let Some(q) = stability_cutoff(me, enemy, alpha) {
+		return q;
+	}
+	
+	// a selective solve skips positions whose score is very likely outside of the window
+	if let Some(margin) = ctx.probcut_margin {
+		if empty_disks >= PROBCUT_MIN_EMPTIES {
+			if let Some(q) = probcut(me, enemy, alpha, beta, margin) {
+				return q;
+			}
+		}
+	}
+	
 	// get possible moves
 	let moves = generate_moves(me, enemy);
 	
+	let mut child_pv = PvLine::new();
+	
 	// if no moves, pass
 	if moves == 0 {
-		return -solve_endgame_mo(enemy, me, -beta, -alpha, stop_mo_at_empties);
+		let q = -solve_endgame_mo(ctx, enemy, me, -beta, -alpha, stop_mo_at_empties, &mut child_pv);
+		pv.set(65, &child_pv);
+		if use_tt {
+			solve_endgame_store(ctx, hash, empty_disks, EgEntry::from_window(q, alpha, beta, 65));
+		}
+		return q;
 	}
 	
 	// apply each move and get the state
-	let mut states: Vec<(u64, u64)> = to_bit_move_vec(moves)
+	let mut states: Vec<(u8, u64, u64)> = to_idx_move_vec(moves)
 		.iter()
-		.map(|mov| make_move(*mov, me, enemy))
+		.map(|mov| {
+			let (new_me, new_enemy) = make_move(1u64 << *mov, me, enemy);
+			(*mov, new_me, new_enemy)
+		})
 		.collect();
 	
 	// sort the child states, best one first
 	// benchmark: sort_by_key=39.54s, sort_unstable_by_key=39.79s, sort_by_cached_key=38.74s
-	states.sort_by_cached_key(|(me, enemy)| heuristic_eg_nega_d1(*enemy, *me));
+	states.sort_by_cached_key(|(_, me, enemy)| heuristic_eg_nega_d1(*enemy, *me));
 	
-	let empty_disks = empty_disks(me, enemy);
+	// the table's best move goes ahead of the heuristic order
+	if let Some(i) = states.iter().position(|(mov, _, _)| *mov == tt_move) {
+		states[..=i].rotate_right(1);
+	}
+	
+	let alpha_orig = alpha;
+	let mut best_score: i8 = -65;
+	let mut best_move: u8 = 65;
 	
 	// for each child state
-	for (me, enemy) in states {
+	for (mov, me, enemy) in states {
 		
 		// stop ordering the moves if the empty disks is smaller than the cutoff
 		let q = if empty_disks > stop_mo_at_empties {
-			-solve_endgame_mo(enemy, me, -beta, -alpha, stop_mo_at_empties)
+			-solve_endgame_mo(ctx, enemy, me, -beta, -alpha, stop_mo_at_empties, &mut child_pv)
 		} else {
-			-solve_endgame_weakmo(enemy, me, -beta, -alpha)
+			-solve_endgame_weakmo(ctx, enemy, me, -beta, -alpha, &mut child_pv)
 		};
 		
 		if q >= beta {
-			return beta; // fail-hard beta-cutoff
+			pv.set(mov, &child_pv);
+			if use_tt {
+				solve_endgame_store(ctx, hash, empty_disks, EgEntry::from_window(q, alpha_orig, beta, mov));
+			}
+			return q; // fail-soft beta-cutoff
 		}
 		
-		if q > alpha {
-			alpha = q;
+		if q > best_score {
+			best_score = q;
+			best_move = mov;
+			pv.set(mov, &child_pv);
+			if q > alpha {
+				alpha = q;
+			}
 		}
 		
 	}
 	
-	return alpha;
+	if use_tt {
+		solve_endgame_store(ctx, hash, empty_disks, EgEntry::from_window(best_score, alpha_orig, beta, best_move));
+	}
+	
+	return best_score;
 	
 }
 
-/// Fail-hard negamax for endgame solving
+/// Fail-soft negamax for endgame solving
 /// Uses a faster, but weaker, move ordering only once
 /// https://www.chessprogramming.org/Alpha-Beta
-fn solve_endgame_weakmo(me: u64, enemy: u64, mut alpha: i8, beta: i8) -> i8 {
+fn solve_endgame_weakmo(ctx: &EgContext, me: u64, enemy: u64, mut alpha: i8, beta: i8, pv: &mut PvLine) -> i8 {
+	
+	// start with an empty line
+	pv.clear();
+	ctx.count_node();
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
 		return evaluation(me, enemy);
 	}
 	
+	if let Some(q) = stability_cutoff(me, enemy, alpha) {
+		return q;
+	}
+	
 	// get possible moves
 	let moves = generate_moves(me, enemy);
 	
+	let mut child_pv = PvLine::new();
+	
 	// if no moves, pass
 	if moves == 0 {
-		return -solve_endgame_nomo(enemy, me, -beta, -alpha);
+		let q = -solve_endgame_nomo(ctx, enemy, me, -beta, -alpha, &mut child_pv);
+		pv.set(65, &child_pv);
+		return q;
 	}
 	
 	// apply each move and get the state
-	let mut states: Vec<(u64, u64)> = to_bit_move_vec(moves)
+	let mut states: Vec<(u8, u64, u64)> = to_idx_move_vec(moves)
 		.iter()
-		.map(|mov| make_move(*mov, me, enemy))
+		.map(|mov| {
+			let (new_me, new_enemy) = make_move(1u64 << *mov, me, enemy);
+			(*mov, new_me, new_enemy)
+		})
 		.collect();
 	
 	// sort the child states, best one first
 	// benchmark: sort_by_key=39.54s, sort_unstable_by_key=39.79s, sort_by_cached_key=38.74s
-	states.sort_by_cached_key(|(me, enemy)| heuristic_eg_nega(*enemy, *me));
+	states.sort_by_cached_key(|(_, me, enemy)| heuristic_eg_nega(*enemy, *me));
+	
+	let mut best_score: i8 = -65;
 	
 	// for each child state
-	for (me, enemy) in states {
+	for (mov, me, enemy) in states {
 		
-		let q = -solve_endgame_nomo(enemy, me, -beta, -alpha);
+		let q = -solve_endgame_nomo(ctx, enemy, me, -beta, -alpha, &mut child_pv);
 		
 		if q >= beta {
-			return beta; // fail-hard beta-cutoff
+			pv.set(mov, &child_pv);
+			return q; // fail-soft beta-cutoff
 		}
 		
-		if q > alpha {
-			alpha = q;
+		if q > best_score {
+			best_score = q;
+			pv.set(mov, &child_pv);
+			if q > alpha {
+				alpha = q;
+			}
 		}
 		
 	}
 	
-	return alpha;
+	return best_score;
 	
 }
 
-/// Fail-hard negamax for endgame solving
-/// Does not use move ordering
+/// Fail-soft negamax for endgame solving
+/// Orders moves by quadrant parity only, positions with few empties use solve_endgame_last
 /// https://www.chessprogramming.org/Alpha-Beta
-fn solve_endgame_nomo(me: u64, enemy: u64, mut alpha: i8, beta: i8) -> i8 {
+fn solve_endgame_nomo(ctx: &EgContext, me: u64, enemy: u64, mut alpha: i8, beta: i8, pv: &mut PvLine) -> i8 {
+	
+	// the last few empties don't need move generation
+	if empty_disks(me, enemy) <= LAST_EMPTIES {
+		return solve_endgame_last(ctx, me, enemy, alpha, beta, pv);
+	}
+	
+	// start with an empty line
+	pv.clear();
+	ctx.count_node();
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
 		return evaluation(me, enemy);
 	}
 	
+	if let Some(q) = stability_cutoff(me, enemy, alpha) {
+		return q;
+	}
+	
 	// get possible moves
-	let mut moves = generate_moves(me, enemy);
+	let moves = generate_moves(me, enemy);
+	
+	let mut child_pv = PvLine::new();
 	
 	// if no moves, pass
 	if moves == 0 {
-		return -solve_endgame_nomo(enemy, me, -beta, -alpha);
+		let q = -solve_endgame_nomo(ctx, enemy, me, -beta, -alpha, &mut child_pv);
+		pv.set(65, &child_pv);
+		return q;
 	}
 	
-	// for each move
-	while moves != 0 {
-		
-		let mov = next_bit_move(&mut moves);
+	let mut best_score: i8 = -65;
+	
+	// for each move, moves in odd quadrants first
+	let odd = odd_quadrants(me, enemy);
+	for mut moves in [moves & odd, moves & !odd] {
+		while moves != 0 {
+			
+			let mov = next_bit_move(&mut moves);
+			
+			let (me, enemy) = make_move(mov, me, enemy);
+			let q = -solve_endgame_nomo(ctx, enemy, me, -beta, -alpha, &mut child_pv);
+			
+			if q >= beta {
+				pv.set(mov.trailing_zeros() as u8, &child_pv);
+				return q; // fail-soft beta-cutoff
+			}
+			
+			if q > best_score {
+				best_score = q;
+				pv.set(mov.trailing_zeros() as u8, &child_pv);
+				if q > alpha {
+					alpha = q;
+				}
+			}
+			
+		}
+	}
+	
+	return best_score;
+	
+}
+
+/// Fail-soft negamax for the last LAST_EMPTIES empties
+/// Tries each empty square instead of generating moves, a square with no flips isn't a move.
+/// Squares in odd quadrants are tried first
+fn solve_endgame_last(ctx: &EgContext, me: u64, enemy: u64, mut alpha: i8, beta: i8, pv: &mut PvLine) -> i8 {
+	
+	// start with an empty line
+	pv.clear();
+	ctx.count_node();
+	
+	let empties = !(me | enemy);
+	if empties.count_ones() <= 1 {
+		return solve_endgame_last_1(me, enemy, empties, pv);
+	}
+	
+	let mut child_pv = PvLine::new();
+	let mut best_score: i8 = -65;
+	let mut has_move = false;
+	
+	let odd = odd_quadrants(me, enemy);
+	for mut squares in [empties & odd, empties & !odd] {
+		while squares != 0 {
+			
+			let mov = next_bit_move(&mut squares);
+			
+			let (new_me, new_enemy) = make_move(mov, me, enemy);
+			if new_enemy == enemy {
+				continue;
+			}
+			has_move = true;
+			
+			let q = -solve_endgame_last(ctx, new_enemy, new_me, -beta, -alpha, &mut child_pv);
+			
+			if q >= beta {
+				pv.set(mov.trailing_zeros() as u8, &child_pv);
+				return q; // fail-soft beta-cutoff
+			}
+			
+			if q > best_score {
+				best_score = q;
+				pv.set(mov.trailing_zeros() as u8, &child_pv);
+				if q > alpha {
+					alpha = q;
+				}
+			}
+			
+		}
+	}
+	
+	if has_move {
+		return best_score;
+	}
+	
+	// pass if the enemy can move, otherwise the game is over
+	if generate_moves(enemy, me) == 0 {
+		return evaluation(me, enemy);
+	}
+	let q = -solve_endgame_last(ctx, enemy, me, -beta, -alpha, &mut child_pv);
+	pv.set(65, &child_pv);
+	return q;
+	
+}
+
+/// Solves a position with a single empty square `mov` (or none)
+/// Exact score, so no window is needed
+#[inline(always)]
+fn solve_endgame_last_1(me: u64, enemy: u64, mov: u64, pv: &mut PvLine) -> i8 {
+	
+	if mov == 0 {
+		return evaluation(me, enemy);
+	}
+	
+	let (new_me, new_enemy) = make_move(mov, me, enemy);
+	if new_enemy != enemy {
+		pv.set_move(mov.trailing_zeros() as u8);
+		return evaluation(new_me, new_enemy);
+	}
+	
+	// pass, the enemy plays the last square if it can
+	let (new_enemy, new_me) = make_move(mov, enemy, me);
+	if new_me != me {
+		let mut child_pv = PvLine::new();
+		child_pv.set_move(mov.trailing_zeros() as u8);
+		pv.set(65, &child_pv);
+		return evaluation(new_me, new_enemy);
+	}
+	
+	return evaluation(me, enemy);
+	
+}
+
+/// Empty squares in quadrants with an odd number of empties
+/// Playing there first tends to leave the last move of each region to us
+/// https://www.chessprogramming.org/Parity
+#[inline(always)]
+fn odd_quadrants(me: u64, enemy: u64) -> u64 {
+	let empties = !(me | enemy);
+	QUADRANTS.iter()
+		.filter(|quadrant| (empties & **quadrant).count_ones() % 2 == 1)
+		.fold(0, |odd, quadrant| odd | quadrant)
+}
+
+/// ProbCut: predicts the score as a shallow heuristic_eg_search plus PROBCUT_BIAS,
+/// the error of the prediction is about normal with a mean of 0 and PROBCUT_SIGMA
+/// Returns the bound of the window if the prediction is more than `margin` centidisks outside of it
+/// Each side of the window is checked with a null window search
+/// https://www.chessprogramming.org/ProbCut
+fn probcut(me: u64, enemy: u64, alpha: i8, beta: i8, margin: i32) -> Option<i8> {
+	
+	// shallow + bias >= beta + margin and shallow + bias <= alpha - margin,
+	// so the bias moves both bounds of the shallow search the same way and the cuts stay centered
+	let high = beta as i32 * 100 + margin - PROBCUT_BIAS;
+	if heuristic_eg_search(me, enemy, PROBCUT_DEPTH, high - 1, high) >= high {
+		return Some(beta);
+	}
+	
+	let low = alpha as i32 * 100 - margin - PROBCUT_BIAS;
+	if heuristic_eg_search(me, enemy, PROBCUT_DEPTH, low, low + 1) <= low {
+		return Some(alpha);
+	}
+	
+	None
+	
+}
+
+/// Returns an upper bound on the score if it is already at or below alpha
+/// The enemy's stable disks are kept to the end of the game, so they cap our disk difference
+#[inline(always)]
+fn stability_cutoff(me: u64, enemy: u64, alpha: i8) -> Option<i8> {
+	
+	// even if every enemy disk was stable, the cap would be above alpha
+	if 64 - 2 * enemy.count_ones() as i32 > alpha as i32 {
+		return None;
+	}
+	
+	let max_score = 64 - 2 * stable_disks(enemy, me).count_ones() as i32;
+	if max_score <= alpha as i32 {
+		return Some(max_score as i8);
+	}
+	
+	None
+	
+}
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	use crate::transposition_table::{TableClient, TranspositionTable};
+	use crate::othello_board::next_idx_move;
+	use std::ops::RangeInclusive;
+	
+	const TT_MB: usize = 16;
+	const NO_LIMITS: EgLimits = EgLimits { max_nodes: None, deadline: None };
+	
+	/// Endgame positions with moves from random games, a few at each of 14 to 18 empties
+	fn test_positions() -> Vec<(u64, u64)> {
+		random_positions(14..=18, 2)
+	}
+	
+	/// Positions with moves from random games, `per_empties` at each number of empties in the range
+	fn random_positions(empties_range: RangeInclusive<u8>, per_empties: usize) -> Vec<(u64, u64)> {
 		
-		let (me, enemy) = make_move(mov, me, enemy);
-		let q = -solve_endgame_nomo(enemy, me, -beta, -alpha);
+		let mut state: u64 = 0x9E3779B97F4A7C15;
+		let mut positions = vec![];
 		
-		if q >= beta {
-			return beta; // fail-hard beta-cutoff
+		for empties in empties_range {
+			let mut found = 0;
+			while found < per_empties {
+				
+				let (mut me, mut enemy) = (0x0000000810000000u64, 0x0000001008000000u64);
+				while empty_disks(me, enemy) > empties && !game_over(me, enemy) {
+					let mut moves = generate_moves(me, enemy);
+					if moves != 0 {
+						state ^= state << 13;
+						state ^= state >> 7;
+						state ^= state << 17;
+						for _ in 0..state % moves.count_ones() as u64 {
+							next_idx_move(&mut moves);
+						}
+						(me, enemy) = make_move(1 << next_idx_move(&mut moves), me, enemy);
+					}
+					(me, enemy) = (enemy, me);
+				}
+				
+				if empty_disks(me, enemy) == empties && generate_moves(me, enemy) != 0 {
+					positions.push((me, enemy));
+					found += 1;
+				}
+				
+			}
 		}
 		
-		if q > alpha {
-			alpha = q;
-		}
+		positions
+		
+	}
+	
+	/// Measures PROBCUT_BIAS and PROBCUT_SIGMA, the mean and the standard deviation of the exact score
+	/// minus the PROBCUT_DEPTH search, on positions of random games at 12 to 16 empties
+	#[test]
+	#[ignore = "slow, run with --ignored --nocapture to print the measured values"]
+	fn probcut_error() {
+		
+		let errors: Vec<f64> = random_positions(12..=16, 200).into_iter()
+			.map(|(me, enemy)| {
+				let exact = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -65, 65, 1, NO_LIMITS).unwrap();
+				(exact.score * 100 - heuristic_eg_search(me, enemy, PROBCUT_DEPTH, -6500, 6500)) as f64
+			})
+			.collect();
 		
+		let n = errors.len() as f64;
+		let mean = errors.iter().sum::<f64>() / n;
+		let sigma = (errors.iter().map(|error| (error - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt();
+		println!("bias {:.0}, sigma {:.0} over {} positions", mean, sigma, errors.len());
+		
+		// within 3 standard errors of the mean, and 5% of sigma
+		assert!((mean - PROBCUT_BIAS as f64).abs() < 3.0 * sigma / n.sqrt());
+		assert!((sigma - PROBCUT_SIGMA).abs() < 0.05 * sigma);
+		
+	}
+	
+	#[test]
+	fn threads_match_single_thread() {
+		for (me, enemy) in test_positions() {
+			let single = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -65, 65, 1, NO_LIMITS).unwrap();
+			let parallel = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -65, 65, 4, NO_LIMITS).unwrap();
+			assert_eq!(parallel.kind, ScoreKind::Exact);
+			assert_eq!((parallel.best_move, parallel.score), (single.best_move, single.score), "{:#018x} {:#018x}", me, enemy);
+		}
 	}
 	
-	return alpha;
+	#[test]
+	fn threads_match_single_thread_on_bounds() {
+		for (me, enemy) in test_positions() {
+			let exact = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -65, 65, 1, NO_LIMITS).unwrap().score as i8;
+			// fail-high, fail-low, then a null window on the score
+			for (alpha, beta) in [(exact - 3, exact - 1), (exact + 1, exact + 3), (exact, exact + 1)] {
+				let single = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, alpha, beta, 1, NO_LIMITS).unwrap();
+				let parallel = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, alpha, beta, 4, NO_LIMITS).unwrap();
+				assert_eq!(single.kind, ScoreKind::Bound);
+				assert_eq!(single.score, if exact >= beta { beta } else { alpha } as i32);
+				assert_eq!((parallel.best_move, parallel.score, parallel.kind), (single.best_move, single.score, single.kind), "{:#018x} {:#018x} ({}, {})", me, enemy, alpha, beta);
+			}
+		}
+	}
+	
+	#[test]
+	fn wld_first_matches_full_window() {
+		for (me, enemy) in test_positions() {
+			let full = solve_endgame_root(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -65, 65, 1, NO_LIMITS).unwrap();
+			let wld_first = solve_endgame_wld_first(TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, true, 1, NO_LIMITS).unwrap();
+			assert_eq!(wld_first.kind, ScoreKind::Exact);
+			assert_eq!((wld_first.best_move, wld_first.score), (full.best_move, full.score), "{:#018x} {:#018x}", me, enemy);
+		}
+	}
 	
 }
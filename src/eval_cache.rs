@@ -0,0 +1,173 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::neural_heuristic::NNBackend;
+use crate::othello_symmetry::sym_canonical_board;
+use crate::transposition_table::hash_position;
+
+/// Size of a slot in bytes
+const SLOT_SIZE: usize = 16;
+
+/// Set in the data of every stored eval, so an empty slot (data = 0) is never a hit
+const OCCUPIED: u64 = 1 << 32;
+
+/// A single eval, the key is stored xor the data like in the transposition table,
+/// so a torn write from another thread is seen as a miss instead of a wrong eval
+struct Slot {
+	key: AtomicU64,
+	data: AtomicU64
+}
+
+/// Lockless cache of network evals, shared by all searches and kept between moves
+/// Boards are stored by their canonical symmetry, so the 8 symmetries of a board share one eval.
+/// Each slot holds a single board, a new eval always replaces the old one.
+pub struct EvalCache {
+	slots: Box<[Slot]>,
+	mask: usize,
+	/// totals of all searches, for the hit rate since the start
+	hits: AtomicU64,
+	lookups: AtomicU64
+}
+
+impl EvalCache {
+	
+	/// Creates a cache that uses at most `mb` megabytes, rounded down to a power of 2 slots
+	/// A cache of 0 megabytes stores nothing
+	pub fn with_size_mb(mb: usize) -> Self {
+		
+		let num_slots: usize = match mb * 1024 * 1024 / SLOT_SIZE {
+			0 => 0,
+			max_slots => 1 << (usize::BITS - 1 - max_slots.leading_zeros())
+		};
+		
+		let slots = (0..num_slots)
+			.map(|_| Slot { key: AtomicU64::new(0), data: AtomicU64::new(0) })
+			.collect();
+		
+		EvalCache {
+			slots,
+			mask: num_slots.saturating_sub(1),
+			hits: AtomicU64::new(0),
+			lookups: AtomicU64::new(0)
+		}
+		
+	}
+	
+	/// Size of the cache in bytes
+	pub fn size_bytes(&self) -> usize {
+		self.slots.len() * SLOT_SIZE
+	}
+	
+	/// Looks up the eval of a canonical board's hash
+	#[inline(always)]
+	fn probe(&self, hash: u64) -> Option<f32> {
+		
+		let slot = self.slots.get(hash as usize & self.mask)?;
+		let data = slot.data.load(Ordering::Relaxed);
+		
+		if data == 0 || slot.key.load(Ordering::Relaxed) ^ data != hash {
+			return None;
+		}
+		
+		Some(f32::from_bits(data as u32))
+		
+	}
+	
+	#[inline(always)]
+	fn store(&self, hash: u64, q: f32) {
+		if let Some(slot) = self.slots.get(hash as usize & self.mask) {
+			let data = OCCUPIED | q.to_bits() as u64;
+			slot.key.store(hash ^ data, Ordering::Relaxed);
+			slot.data.store(data, Ordering::Relaxed);
+		}
+	}
+	
+	/// Hit rate of all searches so far, 0 before any lookup
+	pub fn hit_rate(&self) -> f64 {
+		let lookups = self.lookups.load(Ordering::Relaxed);
+		if lookups == 0 {
+			return 0.0;
+		}
+		self.hits.load(Ordering::Relaxed) as f64 / lookups as f64
+	}
+	
+}
+
+/// Xor'd into the keys of evals averaged over `symmetries` symmetries, 0 for single evals
+/// so averaged and single evals of a board are kept apart in the cache and the transposition table
+pub fn symmetry_salt(symmetries: usize) -> u64 {
+	(symmetries as u64 - 1).wrapping_mul(0x9E3779B97F4A7C15)
+}
+
+/// A backend that answers evals from the cache and only sends the misses to `model`
+/// Used by a single search, which counts its own hits
+pub struct CachedBackend<'a> {
+	model: &'a dyn NNBackend,
+	cache: &'a EvalCache,
+	/// xor'd into the keys, so evals averaged over different numbers of symmetries are kept apart
+	key_salt: u64,
+	hits: Cell<u64>,
+	lookups: Cell<u64>
+}
+
+impl<'a> CachedBackend<'a> {
+	
+	/// `model` averages its evals over `symmetries` symmetries of the board, 1 for none
+	pub fn new(model: &'a dyn NNBackend, cache: &'a EvalCache, symmetries: usize) -> Self {
+		CachedBackend {
+			model,
+			cache,
+			key_salt: symmetry_salt(symmetries),
+			hits: Cell::new(0),
+			lookups: Cell::new(0)
+		}
+	}
+	
+	/// (hits, lookups) of this search
+	pub fn stats(&self) -> (u64, u64) {
+		(self.hits.get(), self.lookups.get())
+	}
+	
+}
+
+impl Drop for CachedBackend<'_> {
+	fn drop(&mut self) {
+		self.cache.hits.fetch_add(self.hits.get(), Ordering::Relaxed);
+		self.cache.lookups.fetch_add(self.lookups.get(), Ordering::Relaxed);
+	}
+}
+
+impl NNBackend for CachedBackend<'_> {
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		
+		let mut evals = vec![0.0; boards.len()];
+		
+		// misses are evaluated in their canonical symmetry,
+		// so a board gets the same eval whichever of its symmetries was seen first
+		let mut misses = Vec::new();
+		let mut miss_indices = Vec::new();
+		for (i, (me, enemy)) in boards.iter().enumerate() {
+			let canonical = sym_canonical_board(*me, *enemy);
+			match self.cache.probe(hash_position(canonical.0, canonical.1) ^ self.key_salt) {
+				Some(q) => evals[i] = q,
+				None => {
+					misses.push(canonical);
+					miss_indices.push(i);
+				}
+			}
+		}
+		
+		self.lookups.set(self.lookups.get() + boards.len() as u64);
+		self.hits.set(self.hits.get() + (boards.len() - misses.len()) as u64);
+		
+		if !misses.is_empty() {
+			let predictions = self.model.predict(&misses);
+			for ((i, (me, enemy)), q) in miss_indices.iter().zip(misses.iter()).zip(predictions.iter()) {
+				self.cache.store(hash_position(*me, *enemy) ^ self.key_salt, *q);
+				evals[*i] = *q;
+			}
+		}
+		
+		evals
+		
+	}
+}
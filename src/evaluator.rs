@@ -0,0 +1,179 @@
+use crate::endgame::heuristic_eg_nega;
+use crate::eval_cache::symmetry_salt;
+use crate::model_pool::NNProvider;
+use crate::neural_heuristic::{nnpredict_batch, nnpredict_d1, nnpredict_dn, NNBackend};
+use crate::othello_board::{generate_moves, number_of_moves};
+use crate::transposition_table::hash_position;
+
+/// Scores positions for the midgame search, in centidisks from the POV of `me`
+/// The search is generic over it, so it runs with or without the neural network
+pub trait Evaluator {
+	
+	/// Eval of a leaf of the search
+	fn evaluate(&self, me: u64, enemy: u64) -> i32;
+	
+	/// Evals of many positions at once, used to order the moves of a node
+	/// Returns one eval per board, in the same order
+	fn evaluate_batch(&self, boards: &[(u64, u64)]) -> Vec<i32> {
+		boards.iter().map(|(me, enemy)| self.evaluate(*me, *enemy)).collect()
+	}
+	
+	/// Plies the eval of a leaf searches ahead of the leaf, which count towards the depth of a search
+	fn leaf_plies(&self) -> u8 {
+		0
+	}
+	
+	/// Xor'd into the transposition table keys of the search, so searches that eval differently
+	/// (like averaging over symmetries) don't take each other's scores
+	fn key_salt(&self) -> u64 {
+		0
+	}
+	
+}
+
+/// The neural network, through any backend (TorchScript model, native, batched or cached)
+pub struct NNEvaluator<'a> {
+	model: &'a dyn NNBackend,
+	key_salt: u64
+}
+
+impl<'a> NNEvaluator<'a> {
+	/// `model` averages its evals over `symmetries` symmetries of the board, 1 for none
+	pub fn new(model: &'a dyn NNBackend, symmetries: usize) -> Self {
+		NNEvaluator { model, key_salt: symmetry_salt(symmetries) }
+	}
+}
+
+impl Evaluator for NNEvaluator<'_> {
+	
+	/// Batches the children of the leaf (3 plies deep on a GPU) and does a negamax on their evals
+	fn evaluate(&self, me: u64, enemy: u64) -> i32 {
+		if cfg!(feature = "large_batch") {
+			// optimal batch depth is 3 for a GPU
+			nnpredict_dn(self.model, me, enemy, 3)
+		} else {
+			// optimal batch depth is 1 for CPU
+			nnpredict_d1(self.model, me, enemy)
+		}
+	}
+	
+	fn evaluate_batch(&self, boards: &[(u64, u64)]) -> Vec<i32> {
+		nnpredict_batch(self.model, boards)
+			.iter()
+			.map(|q| (100.0 * 64.0 * q) as i32)
+			.collect()
+	}
+	
+	fn leaf_plies(&self) -> u8 {
+		if cfg!(feature = "large_batch") { 3 } else { 1 }
+	}
+	
+	fn key_salt(&self) -> u64 {
+		self.key_salt
+	}
+	
+}
+
+/// The pattern weights of the endgame solver's move ordering, see heuristic_eg_nega
+/// Tuned for 7-25 empties, so it is weaker earlier in the game
+pub struct PatternEvaluator;
+
+impl Evaluator for PatternEvaluator {
+	fn evaluate(&self, me: u64, enemy: u64) -> i32 {
+		heuristic_eg_nega(me, enemy)
+	}
+}
+
+/// Centidisks per disk of the disk/mobility evaluator
+const DISK_WEIGHT: i32 = 20;
+/// Centidisks per move more than the enemy has
+const MOBILITY_WEIGHT: i32 = 100;
+
+/// Disk difference and mobility, the simplest midgame eval
+pub struct DiskMobilityEvaluator;
+
+impl Evaluator for DiskMobilityEvaluator {
+	fn evaluate(&self, me: u64, enemy: u64) -> i32 {
+		let disks = me.count_ones() as i32 - enemy.count_ones() as i32;
+		let mobility = generate_moves(me, enemy).count_ones() as i32 - number_of_moves(enemy, me) as i32;
+		DISK_WEIGHT * disks + MOBILITY_WEIGHT * mobility
+	}
+}
+
+/// Centidisk weights of the classic evaluator
+const CLASSIC_MOBILITY_WEIGHT: i32 = 120;
+const CLASSIC_FRONTIER_WEIGHT: i32 = 40;
+const CLASSIC_CORNER_WEIGHT: i32 = 700;
+/// The classic features are blended into the pattern weights between these numbers of empties
+const CLASSIC_BLEND_START: i32 = 60;
+const CLASSIC_BLEND_END: i32 = 10;
+
+const CORNERS: u64 = 0x8100000000000081;
+const NOT_A_FILE: u64 = 0xFEFEFEFEFEFEFEFE;
+const NOT_H_FILE: u64 = 0x7F7F7F7F7F7F7F7F;
+
+/// Squares next to any square of `bb`, in all 8 directions
+#[inline(always)]
+fn neighbours(bb: u64) -> u64 {
+	let row = bb | ((bb << 1) & NOT_A_FILE) | ((bb >> 1) & NOT_H_FILE);
+	(row | (row << 8) | (row >> 8)) & !bb
+}
+
+/// Mobility, frontier disks (next to an empty square) and corners, blended into the
+/// endgame pattern weights as the board fills, since those are tuned for 7-25 empties
+/// A reasonable midgame eval without the neural network
+pub struct ClassicEvaluator;
+
+impl Evaluator for ClassicEvaluator {
+	fn evaluate(&self, me: u64, enemy: u64) -> i32 {
+		
+		let empty = !(me | enemy);
+		let next_to_empty = neighbours(empty);
+		
+		let mobility = generate_moves(me, enemy).count_ones() as i32 - number_of_moves(enemy, me) as i32;
+		let frontier = (enemy & next_to_empty).count_ones() as i32 - (me & next_to_empty).count_ones() as i32;
+		let corners = (me & CORNERS).count_ones() as i32 - (enemy & CORNERS).count_ones() as i32;
+		let classic = CLASSIC_MOBILITY_WEIGHT * mobility + CLASSIC_FRONTIER_WEIGHT * frontier + CLASSIC_CORNER_WEIGHT * corners;
+		
+		// share of the pattern weights, out of the length of the blend
+		let blend = CLASSIC_BLEND_START - CLASSIC_BLEND_END;
+		let pattern_share = (CLASSIC_BLEND_START - empty.count_ones() as i32).clamp(0, blend);
+		if pattern_share == 0 {
+			return classic;
+		}
+		
+		(classic * (blend - pattern_share) + heuristic_eg_nega(me, enemy) * pattern_share) / blend
+		
+	}
+}
+
+/// A random eval in -6400..=6400 per position, the same every time for the same seed
+/// A baseline for benchmarks, and search behavior doesn't depend on the order positions are evaluated in
+#[derive(Clone, Copy)]
+pub struct RandomEvaluator {
+	seed: u64
+}
+
+impl RandomEvaluator {
+	pub fn new(seed: u64) -> Self {
+		RandomEvaluator { seed }
+	}
+}
+
+impl Evaluator for RandomEvaluator {
+	fn evaluate(&self, me: u64, enemy: u64) -> i32 {
+		let h = hash_position(me ^ self.seed, enemy ^ self.seed.rotate_left(32));
+		(h % 12801) as i32 - 6400
+	}
+}
+
+/// The evaluator of the engine's midgame searches, chosen with --evaluator
+pub enum MidgameEvaluator {
+	/// the neural network, the only one that needs a model
+	NN(Box<NNProvider>),
+	Pattern,
+	DiskMobility,
+	/// also the fallback when the network can't be loaded
+	Classic,
+	Random(RandomEvaluator)
+}
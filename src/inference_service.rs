@@ -0,0 +1,142 @@
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::neural_heuristic::{no_grad, NNBackend};
+
+/// Messages from the searches to the service thread
+enum InferenceMessage {
+	/// a search started and may send predictions
+	Join,
+	/// a search finished
+	Leave,
+	/// boards to evaluate, the evals are sent back on the reply channel
+	Predict(Vec<(u64, u64)>, Sender<Vec<f32>>)
+}
+
+/// Runs the neural network for all concurrent searches on a single thread.
+/// Predictions of different searches are gathered and run as one large batch.
+/// A batch runs once every search is waiting on it, the batch is full,
+/// or the oldest prediction has waited for the max latency.
+pub struct InferenceService {
+	sender: Sender<InferenceMessage>
+}
+
+/// Handle for a single search to send predictions to the service
+/// Must only be held while searching, the service waits for all searches holding one
+pub struct InferenceClient {
+	sender: Sender<InferenceMessage>,
+	reply_sender: Sender<Vec<f32>>,
+	reply_receiver: Receiver<Vec<f32>>
+}
+
+impl InferenceService {
+	
+	/// Starts the service thread, which owns the model
+	/// A batch is run once it has at least `max_batch` boards
+	/// or its first prediction has waited for `max_latency`
+	pub fn start(model: Box<dyn NNBackend + Send>, max_batch: usize, max_latency: Duration) -> Self {
+		
+		let (sender, receiver) = channel();
+		
+		thread::Builder::new()
+			.name("inference".to_string())
+			.spawn(move || {
+				no_grad(|| {
+					inference_loop(&*model, receiver, max_batch, max_latency);
+				});
+			})
+			.expect("failed to start inference thread");
+		
+		InferenceService {
+			sender
+		}
+		
+	}
+	
+	/// Creates a handle for a new search
+	pub fn client(&self) -> InferenceClient {
+		
+		let (reply_sender, reply_receiver) = channel();
+		
+		// the service holds batches for searches that joined
+		self.sender.send(InferenceMessage::Join).expect("inference thread stopped");
+		
+		InferenceClient {
+			sender: self.sender.clone(),
+			reply_sender,
+			reply_receiver
+		}
+		
+	}
+	
+}
+
+impl NNBackend for InferenceClient {
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		self.sender.send(InferenceMessage::Predict(boards.to_vec(), self.reply_sender.clone()))
+			.expect("inference thread stopped");
+		self.reply_receiver.recv()
+			.expect("inference thread stopped")
+	}
+}
+
+impl Drop for InferenceClient {
+	fn drop(&mut self) {
+		// the service may already be gone if the server is shutting down
+		let _ = self.sender.send(InferenceMessage::Leave);
+	}
+}
+
+/// Gathers predictions into batches and runs them until every sender is dropped
+fn inference_loop(model: &dyn NNBackend, receiver: Receiver<InferenceMessage>, max_batch: usize, max_latency: Duration) {
+	
+	// number of searches that joined and haven't left
+	let mut searches: usize = 0;
+	
+	// pending predictions, each with the number of boards and where to send the evals
+	let mut boards: Vec<(u64, u64)> = Vec::with_capacity(max_batch);
+	let mut replies: Vec<(usize, Sender<Vec<f32>>)> = vec![];
+	let mut deadline = Instant::now();
+	
+	loop {
+		
+		// wait for the next message, only until the deadline if a batch is pending
+		let message = if replies.is_empty() {
+			receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+		} else {
+			receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+		};
+		
+		match message {
+			Ok(InferenceMessage::Join) => searches += 1,
+			Ok(InferenceMessage::Leave) => searches -= 1,
+			Ok(InferenceMessage::Predict(predict_boards, reply)) => {
+				if replies.is_empty() {
+					deadline = Instant::now() + max_latency;
+				}
+				replies.push((predict_boards.len(), reply));
+				boards.extend(predict_boards);
+			}
+			Err(RecvTimeoutError::Timeout) => {}
+			Err(RecvTimeoutError::Disconnected) => return
+		}
+		
+		// no other search can add to the batch once every search is waiting on it
+		let ready = replies.len() >= searches || boards.len() >= max_batch || Instant::now() >= deadline;
+		if replies.is_empty() || !ready {
+			continue;
+		}
+		
+		let evals = model.predict(&boards);
+		
+		// split the evals between the searches, a search that hung up is ignored
+		let mut start = 0;
+		for (len, reply) in replies.drain(..) {
+			let _ = reply.send(evals[start..start + len].to_vec());
+			start += len;
+		}
+		boards.clear();
+		
+	}
+	
+}
@@ -7,11 +7,62 @@ mod server;
 mod opening_book;
 mod othello_symmetry;
 mod board_consts;
+mod time_manager;
+mod protocol;
+mod nboard;
+mod search_result;
+mod config;
+mod model_pool;
+mod inference_service;
+mod search_deadline;
+mod transposition_table;
+mod eval_cache;
+mod evaluator;
+mod native_network;
+mod quantized_network;
 
+use crate::config::{EngineConfig, USAGE};
+use crate::nboard::nboard_start;
+use crate::neural_heuristic::no_grad;
+use crate::quantized_network::quantization_report;
 use crate::server::server_start;
 
 fn main() {
 	
+	let config = match EngineConfig::from_args(std::env::args().skip(1)) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}\n\n{}", e, USAGE);
+			std::process::exit(2);
+		}
+	};
+	
+	if config.help {
+		println!("{}", USAGE);
+		return;
+	}
+	
+	#[cfg(feature = "torch")]
+	if let Some(threads) = config.torch_threads {
+		tch::set_num_threads(threads);
+	}
+	
+	if config.quant_report {
+		if let Err(e) = quantization_report(&config) {
+			eprintln!("Failed to load weights {}: {}", config.weights_path, e);
+			std::process::exit(1);
+		}
+		return;
+	}
+	
+	// run as an NBoard engine over stdin/stdout
+	if config.nboard {
+		no_grad(|| {
+			nboard_start(&config);
+		});
+		return;
+	}
+	
 	// print out compiled features
 	#[cfg(feature = "gpu")] {
 		println!("Feature \"gpu\" enabled");
@@ -20,20 +71,14 @@ fn main() {
 		println!("Feature \"large_batch\" enabled");
 	}
 	
-	let mut port = 35326u16;
-	
-	// try to set port from CLI args
-	let mut args = std::env::args();
-	if args.len() > 1 {
-		port = args.nth(1)
-			.unwrap()
-			.parse()
-			.expect("provided argument isn't a valid port number");
-	}
-	
 	// start the server
-	tch::no_grad(|| {
-		server_start(port);
+	let result = no_grad(|| {
+		server_start(&config)
 	});
 	
+	if let Err(e) = result {
+		eprintln!("Failed to start server on {}:{}: {}", config.bind, config.port, e);
+		std::process::exit(1);
+	}
+	
 }
@@ -0,0 +1,108 @@
+// only libtorch models are pooled
+#![cfg_attr(not(feature = "torch"), allow(dead_code))]
+
+use std::ops::Deref;
+use std::sync::{Condvar, Mutex};
+use crate::inference_service::InferenceService;
+use crate::native_network::NativeNetwork;
+use crate::quantized_network::QuantizedNetwork;
+use crate::neural_heuristic::NNBackend;
+
+/// A pool of model replicas shared between searches
+/// Each midgame search takes a replica for its whole duration,
+/// so as many midgame searches as replicas can run at once
+pub struct ModelPool {
+	models: Mutex<Vec<Box<dyn NNBackend + Send>>>,
+	/// signaled when a replica is returned to the pool
+	returned: Condvar,
+	size: usize
+}
+
+/// A replica taken from the pool, returned to the pool when dropped
+pub struct PooledModel<'a> {
+	pool: &'a ModelPool,
+	model: Option<Box<dyn NNBackend + Send>>
+}
+
+impl ModelPool {
+	
+	pub fn new(models: Vec<Box<dyn NNBackend + Send>>) -> Self {
+		assert!(!models.is_empty(), "a model pool needs at least one model");
+		let size = models.len();
+		ModelPool {
+			models: Mutex::new(models),
+			returned: Condvar::new(),
+			size
+		}
+	}
+	
+	/// Total number of replicas, including the ones in use
+	pub fn size(&self) -> usize {
+		self.size
+	}
+	
+	/// Takes a replica from the pool, waits for one to be returned if they are all in use
+	pub fn acquire(&self) -> PooledModel<'_> {
+		
+		let mut models = self.models.lock().unwrap();
+		while models.is_empty() {
+			models = self.returned.wait(models).unwrap();
+		}
+		
+		PooledModel {
+			pool: self,
+			model: models.pop()
+		}
+		
+	}
+	
+}
+
+impl Deref for PooledModel<'_> {
+	type Target = dyn NNBackend + Send;
+	
+	fn deref(&self) -> &Self::Target {
+		self.model.as_deref().unwrap()
+	}
+}
+
+impl Drop for PooledModel<'_> {
+	fn drop(&mut self) {
+		if let Some(model) = self.model.take() {
+			self.pool.models.lock().unwrap().push(model);
+			self.pool.returned.notify_one();
+		}
+	}
+}
+
+/// Where midgame searches get their neural network evaluations
+pub enum NNProvider {
+	/// each search takes a model replica from a pool
+	Pool(ModelPool),
+	/// all searches share a batching inference service
+	Batched(InferenceService),
+	/// all searches share the native network, which needs no replicas
+	Native(NativeNetwork),
+	/// the same with the quantized network
+	Quantized(QuantizedNetwork)
+}
+
+impl NNProvider {
+	
+	/// Runs a search with a backend that is held for the whole search
+	pub fn with_backend<T, F: FnOnce(&dyn NNBackend) -> T>(&self, f: F) -> T {
+		match self {
+			NNProvider::Pool(models) => {
+				let model = models.acquire();
+				f(&*model)
+			}
+			NNProvider::Batched(service) => {
+				let client = service.client();
+				f(&client)
+			}
+			NNProvider::Native(network) => f(network),
+			NNProvider::Quantized(network) => f(network)
+		}
+	}
+	
+}
@@ -0,0 +1,387 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use byteorder::{LittleEndian, ReadBytesExt};
+use crate::neural_heuristic::NNBackend;
+
+/// Magic at the start of a weights file written by training/export_weights.py
+/// The file is little-endian:
+/// magic, inputs: u32 (64), hidden: u32, blocks: u32, head: u32,
+/// then the weights ([out][in]) and bias (f32 each) of every Linear layer in order,
+/// then samples: u32 and (me: u64, enemy: u64, eval: f32) evals of the TorchScript model per sample
+pub const WEIGHTS_MAGIC: [u8; 8] = *b"OTHNNW01";
+
+/// Max difference from the TorchScript evals stored in a weights file
+const MAX_SAMPLE_ERROR: f32 = 1e-4;
+
+/// Number of boards the batched kernel runs at once, each weight row is loaded once for all of them
+pub const KERNEL_BOARDS: usize = 4;
+
+/// A Linear layer, the weights are row-major [out][in]
+#[derive(Clone)]
+pub struct Dense {
+	pub inputs: usize,
+	pub outputs: usize,
+	pub weights: Vec<f32>,
+	pub bias: Vec<f32>
+}
+
+impl Dense {
+	
+	fn read<R: Read>(file: &mut R, inputs: usize, outputs: usize) -> std::io::Result<Self> {
+		
+		let mut weights = vec![0.0; inputs * outputs];
+		file.read_f32_into::<LittleEndian>(&mut weights)?;
+		
+		let mut bias = vec![0.0; outputs];
+		file.read_f32_into::<LittleEndian>(&mut bias)?;
+		
+		Ok(Dense { inputs, outputs, weights, bias })
+		
+	}
+	
+	pub fn num_params(&self) -> usize {
+		self.weights.len() + self.bias.len()
+	}
+	
+	#[inline(always)]
+	pub fn row(&self, o: usize) -> &[f32] {
+		&self.weights[o * self.inputs..(o + 1) * self.inputs]
+	}
+	
+}
+
+/// The Linear(64, n) + ReLU input layer, stored [square][out] so a board adds the column of each disk
+#[derive(Clone)]
+pub struct InputLayer {
+	weights: Vec<f32>,
+	bias: Vec<f32>
+}
+
+impl InputLayer {
+	
+	/// Transposes the Linear layer
+	fn new(layer: Dense) -> Self {
+		let mut weights = vec![0.0; layer.inputs * layer.outputs];
+		for o in 0..layer.outputs {
+			for (sq, w) in layer.row(o).iter().enumerate() {
+				weights[sq * layer.outputs + o] = *w;
+			}
+		}
+		InputLayer { weights, bias: layer.bias }
+	}
+	
+	pub fn outputs(&self) -> usize {
+		self.bias.len()
+	}
+	
+	pub fn num_params(&self) -> usize {
+		self.weights.len() + self.bias.len()
+	}
+	
+	/// Adds the weights of each disk, positive for ours and negative for the enemy's
+	/// Returns the activations of each board after the ReLU, row-major [board][out]
+	pub fn apply(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		
+		let hidden = self.outputs();
+		
+		let mut x = vec![0.0; boards.len() * hidden];
+		for (row, (me, enemy)) in x.chunks_exact_mut(hidden).zip(boards.iter()) {
+			row.copy_from_slice(&self.bias);
+			for (mut disks, sign) in [(*me, 1.0), (*enemy, -1.0)] {
+				while disks != 0 {
+					let sq = disks.trailing_zeros() as usize;
+					disks &= disks - 1;
+					let column = &self.weights[sq * hidden..(sq + 1) * hidden];
+					for (x, w) in row.iter_mut().zip(column.iter()) {
+						*x += sign * w;
+					}
+				}
+			}
+			relu(row);
+		}
+		
+		x
+		
+	}
+	
+}
+
+/// Matrix-vector kernels, picked for the CPU at load time
+#[derive(Clone, Copy, Debug)]
+pub enum Kernel {
+	Scalar,
+	#[cfg(target_arch = "x86_64")]
+	Avx2
+}
+
+impl Kernel {
+	
+	pub fn detect() -> Self {
+		#[cfg(target_arch = "x86_64")] {
+			if is_x86_feature_detected!("avx2") && is_x86_feature_detected!("fma") {
+				return Kernel::Avx2;
+			}
+		}
+		Kernel::Scalar
+	}
+	
+	/// Dot products of the weight row `w` with each of the inputs
+	#[inline(always)]
+	fn dot4(self, w: &[f32], x: [&[f32]; KERNEL_BOARDS]) -> [f32; KERNEL_BOARDS] {
+		match self {
+			Kernel::Scalar => x.map(|x| dot_scalar(w, x)),
+			// SAFETY: the cpu supports avx2 and fma, checked in detect
+			#[cfg(target_arch = "x86_64")]
+			Kernel::Avx2 => unsafe { dot4_avx2(w, x) }
+		}
+	}
+	
+	#[inline(always)]
+	pub fn dot(self, w: &[f32], x: &[f32]) -> f32 {
+		match self {
+			Kernel::Scalar => dot_scalar(w, x),
+			#[cfg(target_arch = "x86_64")]
+			Kernel::Avx2 => unsafe { dot_avx2(w, x) }
+		}
+	}
+	
+}
+
+/// The network of training/train_network.py, evaluated in Rust instead of libtorch
+/// Linear(64, n) + ReLU, ResBlocks of Linear(n, n) + ReLU + Linear(n, n) + skip + ReLU,
+/// Linear(n, n / 4) + ReLU, Linear(n / 4, 1) + Tanh
+pub struct NativeNetwork {
+	pub input: InputLayer,
+	pub blocks: Vec<(Dense, Dense)>,
+	pub head: Dense,
+	pub output: Dense,
+	kernel: Kernel
+}
+
+impl NativeNetwork {
+	
+	/// Loads a weights file exported by training/export_weights.py
+	/// Fails if the network doesn't reproduce the TorchScript evals stored in the file, or there are none to check
+	pub fn load(path: &str) -> std::io::Result<Self> {
+		
+		let mut file = BufReader::new(File::open(path)?);
+		
+		let mut magic = [0u8; 8];
+		file.read_exact(&mut magic)?;
+		if magic != WEIGHTS_MAGIC {
+			return Err(Error::new(ErrorKind::InvalidData, "not a weights file"));
+		}
+		
+		let inputs = file.read_u32::<LittleEndian>()? as usize;
+		let hidden = file.read_u32::<LittleEndian>()? as usize;
+		let blocks = file.read_u32::<LittleEndian>()? as usize;
+		let head = file.read_u32::<LittleEndian>()? as usize;
+		if inputs != 64 || hidden == 0 || head == 0 {
+			return Err(Error::new(ErrorKind::InvalidData, format!("unsupported network shape {}-{}-{}", inputs, hidden, head)));
+		}
+		
+		let input = InputLayer::new(Dense::read(&mut file, inputs, hidden)?);
+		
+		let blocks = (0..blocks)
+			.map(|_| Ok((Dense::read(&mut file, hidden, hidden)?, Dense::read(&mut file, hidden, hidden)?)))
+			.collect::<std::io::Result<Vec<_>>>()?;
+		
+		let network = NativeNetwork {
+			input,
+			blocks,
+			head: Dense::read(&mut file, hidden, head)?,
+			output: Dense::read(&mut file, head, 1)?,
+			kernel: Kernel::detect()
+		};
+		
+		// the evals of the TorchScript model on the same boards
+		// export_weights.py always writes some, without them a wrong file would load without an error
+		let samples = file.read_u32::<LittleEndian>()? as usize;
+		if samples == 0 {
+			return Err(Error::new(ErrorKind::InvalidData, "no TorchScript evals to check the weights against"));
+		}
+		let mut boards = Vec::with_capacity(samples);
+		let mut expected = Vec::with_capacity(samples);
+		for _ in 0..samples {
+			boards.push((file.read_u64::<LittleEndian>()?, file.read_u64::<LittleEndian>()?));
+			expected.push(file.read_f32::<LittleEndian>()?);
+		}
+		
+		let error = network.predict(&boards)
+			.iter()
+			.zip(expected.iter())
+			.map(|(q, e)| (q - e).abs())
+			.fold(0.0, f32::max);
+		if error > MAX_SAMPLE_ERROR {
+			return Err(Error::new(ErrorKind::InvalidData, format!("evals differ from the TorchScript model by up to {}", error)));
+		}
+		
+		Ok(network)
+		
+	}
+	
+	/// Counts the parameters for nice log messages
+	pub fn num_params(&self) -> usize {
+		self.input.num_params()
+			+ self.blocks.iter().map(|(l1, l2)| l1.num_params() + l2.num_params()).sum::<usize>()
+			+ self.head.num_params() + self.output.num_params()
+	}
+	
+	/// Name of the kernels used on this cpu
+	pub fn kernel_name(&self) -> String {
+		format!("{:?}", self.kernel).to_lowercase()
+	}
+	
+	/// Evaluates the boards, `observe` gets the input of every layer after the input layer in order,
+	/// the two layers of each residual block, then the head
+	pub fn forward<F: FnMut(&[f32])>(&self, boards: &[(u64, u64)], mut observe: F) -> Vec<f32> {
+		
+		let mut x = self.input.apply(boards);
+		
+		// residual blocks, the second layer adds onto the block input
+		let mut t = vec![0.0; x.len()];
+		for (l1, l2) in self.blocks.iter() {
+			observe(&x);
+			t.fill(0.0);
+			self.dense_add(l1, &x, &mut t);
+			relu(&mut t);
+			observe(&t);
+			self.dense_add(l2, &t, &mut x);
+			relu(&mut x);
+		}
+		
+		observe(&x);
+		let mut h = vec![0.0; boards.len() * self.head.outputs];
+		self.dense_add(&self.head, &x, &mut h);
+		relu(&mut h);
+		
+		let mut q = vec![0.0; boards.len()];
+		self.dense_add(&self.output, &h, &mut q);
+		q.iter_mut().for_each(|q| *q = q.tanh());
+		
+		q
+		
+	}
+	
+	/// Adds `layer` applied to each row of `input` to the rows of `out`
+	/// Boards go through in groups of KERNEL_BOARDS, so each weight row is read once per group
+	fn dense_add(&self, layer: &Dense, input: &[f32], out: &mut [f32]) {
+		
+		let (inputs, outputs) = (layer.inputs, layer.outputs);
+		let boards = out.len() / outputs;
+		
+		let mut b = 0;
+		while b + KERNEL_BOARDS <= boards {
+			let x: [&[f32]; KERNEL_BOARDS] = std::array::from_fn(|k| &input[(b + k) * inputs..(b + k + 1) * inputs]);
+			for o in 0..outputs {
+				let q = self.kernel.dot4(layer.row(o), x);
+				for (k, q) in q.iter().enumerate() {
+					out[(b + k) * outputs + o] += layer.bias[o] + q;
+				}
+			}
+			b += KERNEL_BOARDS;
+		}
+		
+		// fewer boards than a group are left
+		for b in b..boards {
+			let x = &input[b * inputs..(b + 1) * inputs];
+			for o in 0..outputs {
+				out[b * outputs + o] += layer.bias[o] + self.kernel.dot(layer.row(o), x);
+			}
+		}
+		
+	}
+	
+}
+
+impl NNBackend for NativeNetwork {
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		self.forward(boards, |_| {})
+	}
+}
+
+#[inline(always)]
+pub fn relu(x: &mut [f32]) {
+	x.iter_mut().for_each(|x| *x = x.max(0.0));
+}
+
+/// Dot product with 8 partial sums, which the compiler can vectorize
+#[inline(always)]
+fn dot_scalar(w: &[f32], x: &[f32]) -> f32 {
+	
+	let mut acc = [0.0f32; 8];
+	let w_chunks = w.chunks_exact(8);
+	let x_chunks = x.chunks_exact(8);
+	let tail: f32 = w_chunks.remainder().iter().zip(x_chunks.remainder().iter()).map(|(w, x)| w * x).sum();
+	
+	for (w, x) in w_chunks.zip(x_chunks) {
+		for ((acc, w), x) in acc.iter_mut().zip(w.iter()).zip(x.iter()) {
+			*acc += w * x;
+		}
+	}
+	
+	acc.iter().sum::<f32>() + tail
+	
+}
+
+/// Dot products of `w` with 4 inputs at once, with 8 wide fused multiply-adds
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot4_avx2(w: &[f32], x: [&[f32]; KERNEL_BOARDS]) -> [f32; KERNEL_BOARDS] {
+	
+	use std::arch::x86_64::*;
+	
+	let n = w.len();
+	let mut acc = [_mm256_setzero_ps(); KERNEL_BOARDS];
+	
+	let mut i = 0;
+	while i + 8 <= n {
+		let wv = _mm256_loadu_ps(w.as_ptr().add(i));
+		for (acc, x) in acc.iter_mut().zip(x.iter()) {
+			*acc = _mm256_fmadd_ps(wv, _mm256_loadu_ps(x.as_ptr().add(i)), *acc);
+		}
+		i += 8;
+	}
+	
+	let mut sums = [0.0; KERNEL_BOARDS];
+	for ((sum, acc), x) in sums.iter_mut().zip(acc.iter()).zip(x.iter()) {
+		*sum = hsum_avx2(*acc) + dot_scalar(&w[i..], &x[i..]);
+	}
+	
+	sums
+	
+}
+
+/// Dot product of `w` and `x` with 8 wide fused multiply-adds
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2,fma")]
+unsafe fn dot_avx2(w: &[f32], x: &[f32]) -> f32 {
+	
+	use std::arch::x86_64::*;
+	
+	let n = w.len();
+	let mut acc = _mm256_setzero_ps();
+	
+	let mut i = 0;
+	while i + 8 <= n {
+		acc = _mm256_fmadd_ps(_mm256_loadu_ps(w.as_ptr().add(i)), _mm256_loadu_ps(x.as_ptr().add(i)), acc);
+		i += 8;
+	}
+	
+	hsum_avx2(acc) + dot_scalar(&w[i..], &x[i..])
+	
+}
+
+/// Sum of the 8 lanes
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn hsum_avx2(v: std::arch::x86_64::__m256) -> f32 {
+	
+	use std::arch::x86_64::*;
+	
+	let v = _mm_add_ps(_mm256_castps256_ps128(v), _mm256_extractf128_ps(v, 1));
+	let v = _mm_add_ps(v, _mm_movehl_ps(v, v));
+	let v = _mm_add_ss(v, _mm_shuffle_ps(v, v, 1));
+	_mm_cvtss_f32(v)
+	
+}
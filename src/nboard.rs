@@ -0,0 +1,394 @@
+use std::io::{BufRead, Write};
+use std::time::{Duration, Instant};
+use crate::config::EngineConfig;
+use crate::othello_board::{empty_disks, BoardKernel};
+use crate::othello_state::OthelloState;
+use crate::search_result::SearchResult;
+use crate::server::{load_book, load_evaluator, server_get_analysis, server_get_move, Engine, EngineStatus, SearchParams};
+use crate::transposition_table::{TableClient, TranspositionTable};
+use crate::eval_cache::EvalCache;
+use crate::time_manager::TimeManager;
+
+/// Name reported to the GUI
+const ENGINE_NAME: &str = "Othello-rs";
+
+/// Midgame depth until the GUI sends "set depth"
+const DEFAULT_MID_DEPTH: u8 = 6;
+
+/// Endgame depth, the GUI only sets the midgame depth
+/// Lowered to the max endgame depth of the config if needed
+const DEFAULT_END_DEPTH: u8 = 20;
+
+/// State of an NBoard session
+struct NBoardEngine {
+	engine: Engine,
+	/// the GUI is the only client of the tables
+	client: TableClient,
+	/// position all commands refer to
+	state: OthelloState,
+	/// midgame depth set by the GUI
+	mid_depth: u8
+}
+
+/// Converts an NBoard square (like "F5") to a move index
+/// Returns 65 for a pass ("PA")
+/// A1 is bit 0 and H8 is bit 63, which matches OthelloState::starting_state
+fn parse_square(square: &str) -> Option<u8> {
+	
+	let square = square.trim().to_ascii_lowercase();
+	if square == "pa" || square == "pass" {
+		return Some(65);
+	}
+	
+	let bytes = square.as_bytes();
+	if bytes.len() != 2 || !(b'a'..=b'h').contains(&bytes[0]) || !(b'1'..=b'8').contains(&bytes[1]) {
+		return None;
+	}
+	
+	let col = bytes[0] - b'a';
+	let row = bytes[1] - b'1';
+	Some(row * 8 + col)
+	
+}
+
+/// Converts a move index to an NBoard square, the inverse of parse_square
+fn square_name(mov: u8) -> String {
+	
+	if mov >= 64 {
+		return "PA".to_string();
+	}
+	
+	let col = (b'A' + mov % 8) as char;
+	let row = (b'1' + mov / 8) as char;
+	format!("{}{}", col, row)
+	
+}
+
+/// Splits a GGF game into its (tag, value) pairs in order
+/// Ex: "(;GM[Othello]B[F5//2.55];)" gives [("GM", "Othello"), ("B", "F5//2.55")]
+fn ggf_tags(ggf: &str) -> Vec<(&str, &str)> {
+	
+	let mut tags = vec![];
+	let mut rest = ggf;
+	
+	while let Some(open) = rest.find('[') {
+		
+		// the tag name is the run of uppercase letters before the bracket
+		let name_start = rest[..open]
+			.rfind(|c: char| !c.is_ascii_uppercase())
+			.map(|i| i + 1)
+			.unwrap_or(0);
+		
+		let close = match rest[open..].find(']') {
+			Some(close) => open + close,
+			None => break
+		};
+		
+		tags.push((&rest[name_start..open], &rest[open + 1..close]));
+		rest = &rest[close + 1..];
+		
+	}
+	
+	tags
+	
+}
+
+/// Applies a move (from a GGF move or a move command) to the state
+/// Passes are inserted if the move is for the other player
+fn apply_square(state: &OthelloState, square: &str, black_moves: Option<bool>) -> Option<OthelloState> {
+	
+	// sync the side to move with the GGF tag, the last player may have passed
+	let mut state = OthelloState::new(state.ply(), state.black(), state.white());
+	if let Some(black_moves) = black_moves {
+		if (state.ply() & 1 == 0) != black_moves {
+			state = state.pass();
+		}
+	}
+	
+	let mov = parse_square(square)?;
+	if mov == 65 {
+		return Some(state.pass());
+	}
+	
+	if !state.available_moves().contains(&mov) {
+		return None;
+	}
+	
+	Some(state.apply_move(mov))
+	
+}
+
+/// Parses a GGF game and returns the position after all the moves
+/// Ex: (;GM[Othello]BO[8 ---------------------------O*------*O--------------------------- *]B[F5//2.55]W[F6];)
+fn parse_ggf(ggf: &str) -> Option<OthelloState> {
+	
+	let mut state = OthelloState::starting_state();
+	
+	for (tag, value) in ggf_tags(ggf) {
+		match tag {
+			// starting board from A1 to H8, then the player to move
+			"BO" => {
+				let mut tokens = value.split_whitespace();
+				if tokens.next() != Some("8") {
+					return None;
+				}
+				let squares: Vec<char> = tokens.flat_map(|t| t.chars()).collect();
+				if squares.len() != 65 {
+					return None;
+				}
+				let mut black = 0u64;
+				let mut white = 0u64;
+				for (i, c) in squares[..64].iter().enumerate() {
+					match c {
+						'*' | 'X' | 'x' | 'B' | 'b' => black |= 1u64 << i,
+						'O' | 'o' | 'W' | 'w' => white |= 1u64 << i,
+						_ => {}
+					}
+				}
+				let ply = if squares[64] == 'O' || squares[64] == 'W' { 1 } else { 0 };
+				state = OthelloState::new(ply, black, white);
+			}
+			// moves are like "F5/eval/time"
+			"B" | "W" => {
+				let square = value.split('/').next()?;
+				state = apply_square(&state, square, Some(tag == "B"))?;
+			}
+			_ => {}
+		}
+	}
+	
+	Some(state)
+	
+}
+
+/// Eval of a search result in disks, a pass has no evaluation
+fn eval_disks(result: &SearchResult) -> f32 {
+	if result.score == i16::MAX as i32 { 0.0 } else { result.score as f32 / 100.0 }
+}
+
+/// Depth of a search line, with the confidence of a selective solve like 24@95%
+fn depth_label(result: &SearchResult, depth: u8) -> String {
+	match result.confidence {
+		Some(confidence) => format!("{}@{}%", depth, confidence.percent()),
+		None => depth.to_string()
+	}
+}
+
+/// Search line of a result, the pv, eval and depth
+fn search_line(result: &SearchResult, depth: u8) -> String {
+	let pv: String = result.pv.iter().map(|mov| square_name(*mov)).collect();
+	format!("search {} {:.2} 0 {}", pv, eval_disks(result), depth_label(result, depth))
+}
+
+impl NBoardEngine {
+	
+	/// Search params for the current settings
+	fn search_params(&self) -> SearchParams {
+		SearchParams {
+			adj_time: false,
+			use_book: true,
+			solve_end_exact: true,
+			solve_end_adaptive: false,
+			mid_depth: self.mid_depth,
+			end_depth: DEFAULT_END_DEPTH.min(self.engine.limits.max_end_depth),
+			extended_reply: true,
+			move_time: None,
+			sym_average: false
+		}
+	}
+	
+	/// Bitboards of the current position as (me, enemy)
+	fn me_enemy(&self) -> (u64, u64) {
+		if self.state.ply() & 1 == 0 {
+			(self.state.black(), self.state.white())
+		} else {
+			(self.state.white(), self.state.black())
+		}
+	}
+	
+	/// Depth of a search of the current position
+	fn search_depth(&self, params: &SearchParams) -> u8 {
+		let (me, enemy) = self.me_enemy();
+		let empties = empty_disks(me, enemy);
+		if empties <= params.end_depth { empties } else { params.mid_depth }
+	}
+	
+	/// Searches the current position
+	/// Returns (search result, eval in disks, depth searched, seconds)
+	fn search(&self) -> (SearchResult, f32, u8, f32) {
+		
+		let (me, enemy) = self.me_enemy();
+		let params = self.search_params();
+		
+		let before = Instant::now();
+		let result = server_get_move(&self.engine, self.client, me, enemy, &params);
+		let secs = before.elapsed().as_secs_f32();
+		
+		let eval = eval_disks(&result);
+		// a selective solve searches to the end, though it is deeper than the exact solver goes
+		let depth = if result.confidence.is_some() { empty_disks(me, enemy) } else { self.search_depth(&params) };
+		
+		(result, eval, depth, secs)
+		
+	}
+	
+	/// Status shown by the GUI between searches, empty unless the engine is degraded
+	fn idle_status(&self) -> String {
+		if self.engine.status.degraded() {
+			format!("status Running {}", self.engine.status)
+		} else {
+			"status".to_string()
+		}
+	}
+	
+	/// Scores the best n moves (all if n = 0) of the current position
+	/// Returns (results best first, depth searched)
+	fn analyze(&self, n: usize) -> (Vec<SearchResult>, u8) {
+		let (me, enemy) = self.me_enemy();
+		let params = self.search_params();
+		let results = server_get_analysis(&self.engine, self.client, me, enemy, &params, n);
+		(results, self.search_depth(&params))
+	}
+	
+	/// Handles a single command from the GUI, writing any responses to out
+	fn handle_command<W: Write>(&mut self, line: &str, out: &mut W) -> std::io::Result<()> {
+		
+		let mut parts = line.trim().splitn(2, ' ');
+		let command = parts.next().unwrap_or("");
+		let args = parts.next().unwrap_or("").trim();
+		
+		match command {
+			"nboard" => {
+				writeln!(out, "set myname {}", ENGINE_NAME)?;
+				writeln!(out, "{}", self.idle_status())?;
+			}
+			"set" => {
+				let mut parts = args.splitn(2, ' ');
+				let setting = parts.next().unwrap_or("");
+				let value = parts.next().unwrap_or("").trim();
+				match setting {
+					"depth" => match value.parse::<u8>() {
+						Ok(depth) => self.mid_depth = depth.clamp(1, self.engine.limits.max_mid_depth),
+						Err(_) => eprintln!("Invalid depth: {}", value)
+					},
+					"game" => match parse_ggf(value) {
+						Some(state) => self.state = state,
+						None => eprintln!("Invalid game: {}", value)
+					},
+					// contempt and anything else is ignored
+					_ => {}
+				}
+			}
+			"move" => {
+				let square = args.split('/').next().unwrap_or("");
+				match apply_square(&self.state, square, None) {
+					Some(state) => self.state = state,
+					None => eprintln!("Illegal move: {}", args)
+				}
+			}
+			"go" => {
+				writeln!(out, "status Thinking")?;
+				out.flush()?;
+				let (result, eval, depth, secs) = self.search();
+				writeln!(out, "{}", search_line(&result, depth))?;
+				writeln!(out, "=== {}/{:.2}/{:.2}", square_name(result.best_move), eval, secs)?;
+				writeln!(out, "{}", self.idle_status())?;
+			}
+			// "hint n" scores the best n moves, each with its own search line
+			"hint" => {
+				writeln!(out, "status Analysing")?;
+				out.flush()?;
+				let n = args.parse::<usize>().unwrap_or(1);
+				let (results, depth) = self.analyze(n);
+				for result in results.iter() {
+					writeln!(out, "{}", search_line(result, depth))?;
+				}
+				writeln!(out, "{}", self.idle_status())?;
+			}
+			"ping" => {
+				writeln!(out, "pong {}", args)?;
+			}
+			"learn" => {
+				writeln!(out, "learned")?;
+			}
+			"" => {}
+			_ => {
+				eprintln!("Unknown command: {}", line);
+			}
+		}
+		
+		out.flush()
+		
+	}
+	
+}
+
+/// Runs the engine with the NBoard protocol over stdin/stdout
+/// Logs go to stderr since stdout is used by the protocol
+pub fn nboard_start(config: &EngineConfig) {
+	
+	eprintln!("Generating moves with the {:?} kernel", BoardKernel::current());
+	
+	let mut status = EngineStatus::default();
+	
+	let (book, description) = load_book(config, &mut status);
+	eprintln!("{}", description);
+	
+	// only one search runs at a time
+	let (evaluator, description) = load_evaluator(config, 1, false, &mut status);
+	eprintln!("Loaded {}", description);
+	if status.degraded() {
+		eprintln!("Running {}", status);
+	}
+	
+	// the tables are kept for the whole session, so later moves of a game reuse them
+	let engine = Engine {
+		book,
+		evaluator,
+		time_manager: TimeManager::new(),
+		mid_tt: TranspositionTable::with_size_mb(config.tt_mb),
+		eg_tt: TranspositionTable::with_size_mb(config.eg_tt_mb),
+		eval_cache: EvalCache::with_size_mb(config.eval_cache_mb),
+		sym_evals: config.sym_evals,
+		eg_threads: config.eg_threads,
+		eg_max_nodes: config.eg_max_nodes,
+		eg_max_time: config.eg_max_ms.map(Duration::from_millis),
+		eg_selective_empties: config.eg_selective_empties,
+		eg_confidence: config.eg_confidence,
+		limits: config.depth_limits,
+		status
+	};
+	
+	let mut session = NBoardEngine {
+		engine,
+		client: TableClient::take_slot(),
+		state: OthelloState::starting_state(),
+		mid_depth: DEFAULT_MID_DEPTH.min(config.depth_limits.max_mid_depth)
+	};
+	
+	let stdin = std::io::stdin();
+	let stdout = std::io::stdout();
+	let mut out = stdout.lock();
+	
+	for line in stdin.lock().lines() {
+		
+		let line = match line {
+			Ok(line) => line,
+			Err(e) => {
+				eprintln!("Failed to read from stdin: {}", e);
+				break;
+			}
+		};
+		
+		if line.trim() == "quit" {
+			break;
+		}
+		
+		if let Err(e) = session.handle_command(&line, &mut out) {
+			eprintln!("Failed to write to stdout: {}", e);
+			break;
+		}
+		
+	}
+	
+}
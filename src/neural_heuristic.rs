@@ -1,9 +1,23 @@
 #![allow(dead_code)]
 
+#[cfg(feature = "torch")]
 use tch::{CModule, Device, Tensor};
 use crate::othello_board::{game_over, generate_moves, make_move, next_bit_move, to_bit_move_vec, wld_evaluation};
+use crate::othello_symmetry::sym_all_boards;
+
+/// Runs `f` without building autograd graphs for libtorch predictions
+#[cfg(feature = "torch")]
+pub fn no_grad<T, F: FnOnce() -> T>(f: F) -> T {
+	tch::no_grad(f)
+}
+
+#[cfg(not(feature = "torch"))]
+pub fn no_grad<T, F: FnOnce() -> T>(f: F) -> T {
+	f()
+}
 
 /// Convert an othello board into a tensor
+#[cfg(feature = "torch")]
 fn board_to_tensor(me: u64, enemy: u64) -> Tensor {
 	
 	let mut data: [f32; 64] = [0.0; 64];
@@ -20,73 +34,97 @@ fn board_to_tensor(me: u64, enemy: u64) -> Tensor {
 	
 }
 
+/// Something that can evaluate othello boards with the neural network
+/// Evals are from the POV of `me` in [-1, 1]
+pub trait NNBackend {
+	/// Performs a prediction on a slice of othello boards (me, enemy) in 1 batch
+	/// Returns a vector q such that `q[i]` is the eval of `boards[i]`
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32>;
+}
+
+#[cfg(feature = "torch")]
+impl NNBackend for CModule {
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		
+		// map boards to tensors
+		let states: Vec<Tensor> = boards
+			.iter()
+			.map(|(m, e)| board_to_tensor(*m, *e))
+			.collect();
+		
+		// stack all board tensors into 1 batch
+		let t = if cfg!(feature = "gpu") {
+			Tensor::stack(&states, 0).to(Device::Cuda(0))
+		} else {
+			Tensor::stack(&states, 0)
+		};
+		
+		// run through model
+		let output: Tensor = if cfg!(feature = "gpu") {
+			self.forward_ts(&[t])
+				.expect("model prediction failed")
+				.to(Device::Cpu)
+		} else {
+			self.forward_ts(&[t])
+				.expect("model prediction failed")
+		};
+		
+		Vec::from(output)
+		
+	}
+}
+
+/// Evaluates a board as the average eval of its first `symmetries` symmetries (1, 2, 4 or 8, see sym_all_boards)
+/// The network isn't perfectly symmetric, so this is more accurate, but `symmetries` times slower
+pub struct SymmetryAveraged<'a> {
+	pub model: &'a dyn NNBackend,
+	pub symmetries: usize
+}
+
+impl NNBackend for SymmetryAveraged<'_> {
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		
+		// the symmetries of all boards go in 1 batch
+		let n = self.symmetries;
+		let all: Vec<(u64, u64)> = boards
+			.iter()
+			.flat_map(|(me, enemy)| sym_all_boards(*me, *enemy).into_iter().take(n))
+			.collect();
+		
+		self.model.predict(&all)
+			.chunks_exact(n)
+			.map(|q| q.iter().sum::<f32>() / n as f32)
+			.collect()
+		
+	}
+}
+
 /// Performs a prediction on a vector othello boards in 1 batch
 /// Returns a vector q such that `q[i]` is the eval of `v[i]`
-pub fn nnpredict_batch(model: &CModule, v: &Vec<(u64, u64)>) -> Vec<f32> {
-	
-	// map boards to tensors
-	let states: Vec<Tensor> = v
-		.iter()
-		.map(|(m, e)| board_to_tensor(*m, *e))
-		.collect();
-	
-	// stack all board tensors into 1 batch
-	let t = if cfg!(feature = "gpu") {
-		Tensor::stack(&states, 0).to(Device::Cuda(0))
-	} else {
-		Tensor::stack(&states, 0)
-	};
-	
-	// run through model
-	let output: Tensor = if cfg!(feature = "gpu") {
-		model.forward_ts(&[t])
-			.expect("model prediction failed")
-			.to(Device::Cpu)
-	} else {
-		model.forward_ts(&[t])
-			.expect("model prediction failed")
-	};
-	
-	Vec::from(output)
-	
+pub fn nnpredict_batch(model: &dyn NNBackend, v: &[(u64, u64)]) -> Vec<f32> {
+	model.predict(v)
 }
 
 /// Performs a prediction on a state. Performs minimax down to depth of `depth`.
 /// All states in the minimax tree are batched and evaluated together.
-pub fn nnpredict_dn(model: &CModule, me: u64, enemy: u64, depth: i8) -> i32 {
+pub fn nnpredict_dn(model: &dyn NNBackend, me: u64, enemy: u64, depth: i8) -> i32 {
 	
 	// TODO: determine optimal capacity
 	// 4096 is sufficient for depth=3
-	let mut tensors: Vec<Tensor> = Vec::with_capacity(4096);
+	let mut boards: Vec<(u64, u64)> = Vec::with_capacity(4096);
 	
 	// negamax to collect batch
-	board_children_to_flat_tensor(&mut tensors, me, enemy, depth);
+	board_children_to_flat_vec(&mut boards, me, enemy, depth);
 	
-	// if there are no moves 3 plys down (even with moves for us), tensors is empty.
+	// if there are no moves 3 plys down (even with moves for us), boards is empty.
 	// If this is the case, stacking will fail. If this is the case, rerun with a lesser depth
 	// Ex: depth=3, fails on (me=18446744043523145728, enemy=3948544) without this check
-	if tensors.is_empty() {
+	if boards.is_empty() {
 		return nnpredict_dn(model, me, enemy, depth - 1);
 	}
 	
-	// stack all states in to 1 batch
-	let t = if cfg!(feature = "gpu") {
-		Tensor::stack(&tensors, 0).to(Device::Cuda(0))
-	} else {
-		Tensor::stack(&tensors, 0)
-	};
-	
-	// run through model
-	let output: Tensor = if cfg!(feature = "gpu") {
-		model.forward_ts(&[t])
-			.expect("model prediction failed")
-			.to(Device::Cpu)
-	} else {
-		model.forward_ts(&[t])
-			.expect("model prediction failed")
-	};
-	
-	let result_vec: Vec<f32> = Vec::from(output);
+	// run all states through the model in 1 batch
+	let result_vec: Vec<f32> = model.predict(&boards);
 	
 	// negamax to evaluate using result from batch
 	(100.0 * 64.0 * negamax_vec(&result_vec, me, enemy, depth, &mut 0)) as i32
@@ -136,17 +174,17 @@ fn negamax_vec(v: &Vec<f32>, me: u64, enemy: u64, depth: i8, vec_idx: &mut usize
 	
 }
 
-/// put all children board tensors to a depth of `depth` into a flat vector.
-fn board_children_to_flat_tensor(v: &mut Vec<Tensor>, me: u64, enemy: u64, depth: i8) {
+/// put all children boards to a depth of `depth` into a flat vector.
+fn board_children_to_flat_vec(v: &mut Vec<(u64, u64)>, me: u64, enemy: u64, depth: i8) {
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
 		return;
 	}
 	
-	// if the depth is 0, push the tensor to the vector
+	// if the depth is 0, push the board to the vector
 	if depth <= 0 {
-		v.push(board_to_tensor(me, enemy));
+		v.push((me, enemy));
 		return;
 	}
 	
@@ -155,7 +193,7 @@ fn board_children_to_flat_tensor(v: &mut Vec<Tensor>, me: u64, enemy: u64, depth
 	
 	// if no moves, pass
 	if moves == 0 {
-		return board_children_to_flat_tensor(v, enemy, me, depth - 1);
+		return board_children_to_flat_vec(v, enemy, me, depth - 1);
 	}
 	
 	// for each move
@@ -164,7 +202,7 @@ fn board_children_to_flat_tensor(v: &mut Vec<Tensor>, me: u64, enemy: u64, depth
 		let mov = next_bit_move(&mut moves);
 		
 		let (m, e) = make_move(mov, me, enemy);
-		board_children_to_flat_tensor(v, e, m, depth - 1);
+		board_children_to_flat_vec(v, e, m, depth - 1);
 		
 	}
 	
@@ -172,7 +210,7 @@ fn board_children_to_flat_tensor(v: &mut Vec<Tensor>, me: u64, enemy: u64, depth
 
 /// Perform a prediction on an othello board
 /// Goes down 1 depth and runs the prediction on all children in 1 batch
-pub fn nnpredict_d1(model: &CModule, me: u64, enemy: u64) -> i32 {
+pub fn nnpredict_d1(model: &dyn NNBackend, me: u64, enemy: u64) -> i32 {
 	
 	// get all moves
 	let moves = generate_moves(me, enemy);
@@ -182,32 +220,20 @@ pub fn nnpredict_d1(model: &CModule, me: u64, enemy: u64) -> i32 {
 		return 100 * (wld_evaluation(me, enemy) as i32);
 	}
 	
-	// Get all the state's children to tensors
-	let states: Vec<Tensor> = to_bit_move_vec(moves)
+	// Get all the state's children from the child's POV
+	let states: Vec<(u64, u64)> = to_bit_move_vec(moves)
 		.iter()
 		.map(|mov| {
 			let (enemy, me) = make_move(*mov, me, enemy);
-			board_to_tensor(me, enemy)
+			(me, enemy)
 		})
 		.collect();
 	
-	// move all tensors into 1 batch
-	let t = if cfg!(feature = "gpu") {
-		Tensor::stack(&states, 0).to(Device::Cuda(0))
-	} else {
-		Tensor::stack(&states, 0)
-	};
-	
 	// perform prediction on batch & do 1 level of negamax
-	let output: Tensor = model.forward_ts(&[t])
-		.expect("model prediction failed");
-	
-	let best_child: Tensor = if cfg!(feature = "gpu") {
-		output.min().to(Device::Cpu)
-	} else {
-		output.min()
-	};
+	let best_child = model.predict(&states)
+		.into_iter()
+		.fold(f32::INFINITY, f32::min);
 	
-	(-100.0 * 64.0 * f32::from(best_child)) as i32
+	(-100.0 * 64.0 * best_child) as i32
 	
 }
@@ -1,47 +1,209 @@
-use tch::CModule;
-use crate::neural_heuristic::{nnpredict_batch, nnpredict_d1, nnpredict_dn};
-use crate::othello_board::{game_over, generate_moves, make_move, next_bit_move, to_bit_move_vec, to_idx_move_vec, wld_evaluation};
+use crate::evaluator::Evaluator;
+use crate::othello_board::{game_over, generate_moves, make_move, next_bit_move, to_idx_move_vec, wld_evaluation};
+use crate::search_result::{PvLine, ScoreKind, SearchResult};
+use crate::search_deadline::SearchDeadline;
+use crate::transposition_table::{hash_position, Bound, ClientTable, MidEntry};
+use std::time::Instant;
 
-/// optimal depth until the bottom of the tree to stop move ordering
-const BEST_STOP_MO_AT_DEPTH: i8 = if cfg!(feature = "large_batch") {
-	2 // optimal mo cutoff is 2 for large batch
-} else {
-	2 // optimal mo cutoff is 2 for cpu
-};
+/// optimal depth until the bottom of the tree to stop move ordering, for large batches and cpu alike
+const BEST_STOP_MO_AT_DEPTH: i8 = 2;
 
-/// Use neural network to evaluate a state
-#[inline(always)]
-fn nnsearch_heuristic(model: &CModule, me: u64, enemy: u64) -> i32 {
-	if cfg!(feature = "large_batch") {
-		// optimal batch depth is 3 for a GPU
-		nnpredict_dn(model, me, enemy, 3)
-	} else {
-		// optimal batch depth is 1 for CPU
-		nnpredict_d1(model, me, enemy)
+/// State shared by all nodes of a single search
+struct NNSearchContext<'a, E: Evaluator + ?Sized> {
+	eval: &'a E,
+	tt: ClientTable<'a>,
+	/// xor'd into the table keys, see Evaluator::key_salt
+	key_salt: u64,
+	/// depth until the bottom of the tree where nnsearch_mo stops ordering the moves
+	stop_mo_at_depth: i8,
+	deadline: SearchDeadline
+}
+
+impl<'a, E: Evaluator + ?Sized> NNSearchContext<'a, E> {
+	
+	fn new(eval: &'a E, tt: ClientTable<'a>, deadline: SearchDeadline) -> Self {
+		NNSearchContext {
+			eval,
+			tt,
+			key_salt: eval.key_salt(),
+			stop_mo_at_depth: BEST_STOP_MO_AT_DEPTH,
+			deadline
+		}
+	}
+	
+	/// Table key of a position
+	#[inline(always)]
+	fn hash(&self, me: u64, enemy: u64) -> u64 {
+		hash_position(me, enemy) ^ self.key_salt
 	}
+	
 }
 
 /// Perform a mid-game evaluation on a node
 /// Call with alpha=-640000, beta=640000
-/// Returns (move, centidisk eval from current player's POV)
-pub fn nnsearch_root(model: &CModule, me: u64, enemy: u64, mut alpha: i32, beta: i32, depth: i8) -> (u8, i32) {
+/// Returns the best move, centidisk eval from current player's POV and the principal variation
+pub fn nnsearch_root<E: Evaluator + ?Sized>(eval: &E, tt: ClientTable, me: u64, enemy: u64, alpha: i32, beta: i32, depth: i8) -> SearchResult {
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
-		return (65, 100 * (wld_evaluation(me, enemy) as i32));
+		return SearchResult { best_move: 65, score: 100 * (wld_evaluation(me, enemy) as i32), pv: vec![], kind: ScoreKind::Heuristic, confidence: None };
 	}
 	
-	// if the depth is 0, evaluate the position with the nn
+	// if the depth is 0, evaluate the position with the evaluator
 	if depth <= 0 {
-		return (65, nnsearch_heuristic(model, me, enemy));
+		return SearchResult { best_move: 65, score: eval.evaluate(me, enemy), pv: vec![], kind: ScoreKind::Heuristic, confidence: None };
 	}
 	
-	// get possible moves
-	let moves = generate_moves(me, enemy);
+	tt.new_search();
+	let ctx = NNSearchContext::new(eval, tt, SearchDeadline::none());
+	let mut children = root_sorted_children(eval, me, enemy, generate_moves(me, enemy));
+	
+	nnsearch_root_children(&ctx, me, enemy, &mut children, alpha, beta, depth)
+	
+}
+
+/// Iterative deepening over nnsearch_root, up to a depth of max_depth
+/// No new iteration is started after soft_deadline, and an iteration still running at
+/// hard_deadline is stopped. The first iteration always completes.
+/// Returns the result of the deepest completed iteration and its depth
+pub fn nnsearch_root_iterative<E: Evaluator + ?Sized>(eval: &E, tt: ClientTable, me: u64, enemy: u64, max_depth: i8, soft_deadline: Instant, hard_deadline: Instant) -> (SearchResult, i8) {
+	
+	if game_over(me, enemy) || max_depth <= 1 {
+		return (nnsearch_root(eval, tt, me, enemy, -640000, 640000, max_depth), max_depth.max(0));
+	}
+	
+	tt.new_search();
+	let mut children = root_sorted_children(eval, me, enemy, generate_moves(me, enemy));
+	
+	// the first iteration isn't stopped, so there is always a move to return
+	let first = NNSearchContext::new(eval, tt, SearchDeadline::none());
+	let mut best = nnsearch_root_children(&first, me, enemy, &mut children, -640000, 640000, 1);
+	let mut completed = 1;
+	
+	let ctx = NNSearchContext::new(eval, tt, SearchDeadline::at(hard_deadline));
+	
+	for depth in 2..=max_depth {
+		
+		if Instant::now() >= soft_deadline {
+			break;
+		}
+		
+		// children are ordered by the scores of the last iteration
+		let result = nnsearch_root_children(&ctx, me, enemy, &mut children, -640000, 640000, depth);
+		
+		// a stopped iteration only searched some moves
+		if ctx.deadline.expired() {
+			break;
+		}
+		
+		best = result;
+		completed = depth;
+		
+	}
+	
+	(best, completed)
+	
+}
+
+/// Searches the root children in the given order, see nnsearch_root
+/// Afterwards the children are sorted by their scores, best one first, to order the next iteration
+/// A position without moves has no children and is searched as a pass
+fn nnsearch_root_children<E: Evaluator + ?Sized>(ctx: &NNSearchContext<E>, me: u64, enemy: u64, children: &mut [(u8, u64, u64, i32)], mut alpha: i32, beta: i32, depth: i8) -> SearchResult {
+	
+	let mut pv = PvLine::new();
+	let mut child_pv = PvLine::new();
 	
 	// if no moves, pass
+	if children.is_empty() {
+		let q = -nnsearch_mo(ctx, enemy, me, -beta, -alpha, depth - 1, &mut child_pv);
+		pv.set(65, &child_pv);
+		return SearchResult { best_move: 65, score: q, pv: pv.moves().to_vec(), kind: ScoreKind::Heuristic, confidence: None };
+	}
+	
+	let mut best_score = -640000;
+	let mut best_move = 65;
+	
+	// for each child state
+	for (mov, me, enemy, key) in children.iter_mut() {
+		
+		let q = -nnsearch_mo(ctx, *enemy, *me, -beta, -alpha, depth - 1, &mut child_pv);
+		*key = q;
+		
+		if q >= beta {
+			best_score = q;
+			best_move = *mov;
+			pv.set(*mov, &child_pv);
+			break;
+		}
+		
+		if q > best_score {
+			best_score = q;
+			best_move = *mov;
+			pv.set(*mov, &child_pv);
+			if q > alpha {
+				alpha = q;
+			}
+		}
+		
+	}
+	
+	// best move first, then by score (an upper bound for moves that failed low)
+	children.sort_by_key(|(mov, _, _, q)| (*mov != best_move, -(*q)));
+	
+	return SearchResult { best_move, score: best_score, pv: pv.moves().to_vec(), kind: ScoreKind::Heuristic, confidence: None };
+	
+}
+
+/// Scores every root move, or only the best `n` moves if n > 0
+/// Each move is searched with a window above the n-th best score found so far,
+/// so every returned move has an exact score. A move that fails low can't be in the top n.
+/// Returns one result per move (starting with that move), best first
+pub fn nnsearch_root_multipv<E: Evaluator + ?Sized>(eval: &E, tt: ClientTable, me: u64, enemy: u64, depth: i8, n: usize) -> Vec<SearchResult> {
+	
+	let moves = generate_moves(me, enemy);
+	
+	// with no choice of move, this is a normal search
+	if depth <= 0 || moves == 0 {
+		return vec![nnsearch_root(eval, tt, me, enemy, -640000, 640000, depth)];
+	}
+	
+	tt.new_search();
+	let ctx = NNSearchContext::new(eval, tt, SearchDeadline::none());
+	let states = root_sorted_children(eval, me, enemy, moves);
+	let n = if n == 0 { states.len() } else { n };
+	
+	let mut results: Vec<SearchResult> = Vec::with_capacity(states.len());
+	let mut pv = PvLine::new();
+	let mut child_pv = PvLine::new();
+	
+	// for each child state
+	for (mov, me, enemy, _) in states {
+		
+		// only moves at least as good as the current n-th best can be in the top n
+		let alpha = if results.len() >= n { results[n - 1].score - 1 } else { -640000 };
+		
+		let q = -nnsearch_mo(&ctx, enemy, me, -640000, -alpha, depth - 1, &mut child_pv);
+		
+		if q > alpha {
+			pv.set(mov, &child_pv);
+			results.push(SearchResult { best_move: mov, score: q, pv: pv.moves().to_vec(), kind: ScoreKind::Heuristic, confidence: None });
+			// stable sort, so ties stay in search order
+			results.sort_by_key(|r| -r.score);
+			results.truncate(n);
+		}
+		
+	}
+	
+	results
+	
+}
+
+/// Applies each root move and sorts the child states with the evaluator, best one first
+/// Returns (applied move, me, enemy, q), empty if there are no moves
+fn root_sorted_children<E: Evaluator + ?Sized>(eval: &E, me: u64, enemy: u64, moves: u64) -> Vec<(u8, u64, u64, i32)> {
+	
 	if moves == 0 {
-		return (65, -nnsearch_mo(model, enemy, me, -beta, -alpha, depth - 1, BEST_STOP_MO_AT_DEPTH));
+		return vec![];
 	}
 	
 	// apply each move and get the state
@@ -54,86 +216,106 @@ pub fn nnsearch_root(model: &CModule, me: u64, enemy: u64, mut alpha: i32, beta:
 		})
 		.collect();
 	
-	// run a prediction on every state
-	let keys: Vec<f32> = nnpredict_batch(model, &states.iter().map(|(_, m, e)| (*m, *e)).collect());
+	// evaluate every state
+	let keys: Vec<i32> = eval.evaluate_batch(&states.iter().map(|(_, m, e)| (*m, *e)).collect::<Vec<_>>());
 	
-	// map the predictions to the states (applied move, me, enemy, q)
+	// map the evals to the states (applied move, me, enemy, q)
 	let mut states: Vec<(u8, u64, u64, i32)> = states
 		.iter()
 		.zip(keys.iter())
-		.map(|((mov, m, e), q)| (*mov, *m, *e, (100.0 * 64.0 * (*q)) as i32))
+		.map(|((mov, m, e), q)| (*mov, *m, *e, *q))
 		.collect();
 	
 	// sort the child states, best one first
 	// benchmark: sort_by_key=39.54s, sort_unstable_by_key=39.79s, sort_by_cached_key=38.74s
 	states.sort_by_key(|(_, _, _, q)| -(*q));
 	
-	let mut best_score = -640000;
-	let mut best_move = 65;
+	states
 	
-	// for each child state
-	for (mov, me, enemy, _) in states {
-		
-		let q = -nnsearch_mo(model, enemy, me, -beta, -alpha, depth - 1, BEST_STOP_MO_AT_DEPTH);
-		
-		if q >= beta {
-			return (mov, q);
-		}
-		
-		if q > best_score {
-			best_score = q;
-			best_move = mov;
-			if q > alpha {
-				alpha = q;
-			}
-		}
-		
+}
+
+/// Stores the result of a node in the table, unless the search was stopped and the score is meaningless
+/// alpha and beta are the window the node was called with
+#[inline(always)]
+fn nnsearch_store<E: Evaluator + ?Sized>(ctx: &NNSearchContext<E>, hash: u64, depth: i8, score: i32, alpha: i32, beta: i32, best_move: u8) {
+	if !ctx.deadline.expired() {
+		ctx.tt.store_mid(hash, depth, MidEntry { score, bound: Bound::from_window(score, alpha, beta), best_move });
 	}
-	
-	return (best_move, best_score);
-	
 }
 
 /// Get the mid-game evaluation of a board without move ordering
 /// Call with alpha=-640000, beta=640000
-/// returns centidisk eval from current player's POV
-fn nnsearch_nomo(model: &CModule, me: u64, enemy: u64, mut alpha: i32, beta: i32, depth: i8) -> i32 {
+/// returns centidisk eval from current player's POV, the principal variation is written to pv
+fn nnsearch_nomo<E: Evaluator + ?Sized>(ctx: &NNSearchContext<E>, me: u64, enemy: u64, mut alpha: i32, beta: i32, depth: i8, pv: &mut PvLine) -> i32 {
+	
+	// start with an empty line for leaf nodes
+	pv.clear();
+	
+	// stop once out of time, the root throws away the result
+	if ctx.deadline.check() {
+		return 0;
+	}
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
 		return 100 * (wld_evaluation(me, enemy) as i32);
 	}
 	
-	// if the depth is 0, evaluate the position with the nn
+	// a deep enough entry may decide the node without searching
+	let hash = ctx.hash(me, enemy);
+	let (entry, tt_move) = ctx.tt.probe_mid(hash, depth);
+	if let Some(q) = entry.and_then(|entry| entry.cutoff(alpha, beta)) {
+		pv.set_move(tt_move);
+		return q;
+	}
+	
+	// if the depth is 0, evaluate the position with the evaluator
 	if depth <= 0 {
-		return nnsearch_heuristic(model, me, enemy);
+		let q = ctx.eval.evaluate(me, enemy);
+		nnsearch_store(ctx, hash, depth, q, -640000, 640000, 64);
+		return q;
 	}
 	
 	// get possible moves
 	let mut moves = generate_moves(me, enemy);
 	
+	let mut child_pv = PvLine::new();
+	
 	// if no moves, pass
 	if moves == 0 {
-		return -nnsearch_nomo(model, enemy, me, -beta, -alpha, depth - 1);
+		let q = -nnsearch_nomo(ctx, enemy, me, -beta, -alpha, depth - 1, &mut child_pv);
+		pv.set(65, &child_pv);
+		nnsearch_store(ctx, hash, depth, q, alpha, beta, 65);
+		return q;
 	}
 	
+	let alpha_orig = alpha;
 	let mut best_score = -640000;
+	let mut best_move = 65;
+	
+	// try the table's best move first
+	let mut first = if tt_move < 64 { moves & (1u64 << tt_move) } else { 0 };
+	moves ^= first;
 	
 	// for each move
-	while moves != 0 {
+	while first | moves != 0 {
 		
-		let mov = next_bit_move(&mut moves);
+		let mov = if first != 0 { std::mem::take(&mut first) } else { next_bit_move(&mut moves) };
 		
 		// evaluate the child state
 		let (me, enemy) = make_move(mov, me, enemy);
-		let q = -nnsearch_nomo(model, enemy, me, -beta, -alpha, depth - 1);
+		let q = -nnsearch_nomo(ctx, enemy, me, -beta, -alpha, depth - 1, &mut child_pv);
 		
 		if q >= beta {
+			pv.set(mov.trailing_zeros() as u8, &child_pv);
+			nnsearch_store(ctx, hash, depth, q, alpha_orig, beta, mov.trailing_zeros() as u8);
 			return q;
 		}
 		
 		if q > best_score {
 			best_score = q;
+			best_move = mov.trailing_zeros() as u8;
+			pv.set(best_move, &child_pv);
 			if q > alpha {
 				alpha = q;
 			}
@@ -141,70 +323,109 @@ fn nnsearch_nomo(model: &CModule, me: u64, enemy: u64, mut alpha: i32, beta: i32
 		
 	}
 	
+	nnsearch_store(ctx, hash, depth, best_score, alpha_orig, beta, best_move);
+	
 	return best_score;
 	
 }
 
 /// Get the mid-game evaluation of a board with move ordering
 /// Call with alpha=-640000, beta=640000
-/// Optimal stop_mo_at_depth=3
-/// returns centidisk eval from current player's POV
-fn nnsearch_mo(model: &CModule, me: u64, enemy: u64, mut alpha: i32, beta: i32, depth: i8, stop_mo_at_depth: i8) -> i32 {
+/// Stops ordering the moves at ctx.stop_mo_at_depth
+/// returns centidisk eval from current player's POV, the principal variation is written to pv
+fn nnsearch_mo<E: Evaluator + ?Sized>(ctx: &NNSearchContext<E>, me: u64, enemy: u64, mut alpha: i32, beta: i32, depth: i8, pv: &mut PvLine) -> i32 {
+	
+	// start with an empty line for leaf nodes
+	pv.clear();
+	
+	// stop once out of time, the root throws away the result
+	if ctx.deadline.check() {
+		return 0;
+	}
 	
 	// if the game is over, evaluate who won
 	if game_over(me, enemy) {
 		return 100 * (wld_evaluation(me, enemy) as i32);
 	}
 	
-	// if the depth is 0, evaluate the position with the nn
+	// a deep enough entry may decide the node without searching
+	let hash = ctx.hash(me, enemy);
+	let (entry, tt_move) = ctx.tt.probe_mid(hash, depth);
+	if let Some(q) = entry.and_then(|entry| entry.cutoff(alpha, beta)) {
+		pv.set_move(tt_move);
+		return q;
+	}
+	
+	// if the depth is 0, evaluate the position with the evaluator
 	if depth <= 0 {
-		return nnsearch_heuristic(model, me, enemy);
+		let q = ctx.eval.evaluate(me, enemy);
+		nnsearch_store(ctx, hash, depth, q, -640000, 640000, 64);
+		return q;
 	}
 	
 	// get possible moves
 	let moves = generate_moves(me, enemy);
 	
+	let mut child_pv = PvLine::new();
+	
 	// if no moves, pass
 	if moves == 0 {
-		return -nnsearch_mo(model, enemy, me, -beta, -alpha, depth - 1, stop_mo_at_depth);
+		let q = -nnsearch_mo(ctx, enemy, me, -beta, -alpha, depth - 1, &mut child_pv);
+		pv.set(65, &child_pv);
+		nnsearch_store(ctx, hash, depth, q, alpha, beta, 65);
+		return q;
 	}
 	
 	// apply each move and get the state
 	// TODO: optimize sorting here
-	let states: Vec<(u64, u64)> = to_bit_move_vec(moves)
+	let states: Vec<(u8, u64, u64)> = to_idx_move_vec(moves)
 		.iter()
-		.map(|mov| make_move(*mov, me, enemy))
+		.map(|mov| {
+			let (new_me, new_enemy) = make_move(1u64 << *mov, me, enemy);
+			(*mov, new_me, new_enemy)
+		})
 		.collect();
 	
-	let keys: Vec<f32> = nnpredict_batch(model, &states);
+	let keys: Vec<i32> = ctx.eval.evaluate_batch(&states.iter().map(|(_, m, e)| (*m, *e)).collect::<Vec<_>>());
 	
-	let mut states: Vec<(u64, u64, i32)> = states
+	let mut states: Vec<(u8, u64, u64, i32)> = states
 		.iter()
 		.zip(keys.iter())
-		.map(|((m, e), q)| (*m, *e, (100.0 * 64.0 * (*q)) as i32))
+		.map(|((mov, m, e), q)| (*mov, *m, *e, *q))
 		.collect();
 	
 	// sort the child states, best one first
 	// benchmark: sort_by_key=39.54s, sort_unstable_by_key=39.79s, sort_by_cached_key=38.74s
-	states.sort_by_key(|(_, _, q)| -(*q));
+	states.sort_by_key(|(_, _, _, q)| -(*q));
+	
+	// the table's best move goes ahead of the evaluator order
+	if let Some(i) = states.iter().position(|(mov, _, _, _)| *mov == tt_move) {
+		states[..=i].rotate_right(1);
+	}
 	
+	let alpha_orig = alpha;
 	let mut best_score = -640000;
+	let mut best_move = 65;
 	
 	// for each child state
-	for (me, enemy, _) in states {
+	for (mov, me, enemy, _) in states {
 		
-		let q = if depth > stop_mo_at_depth {
-			-nnsearch_mo(model, enemy, me, -beta, -alpha, depth - 1, stop_mo_at_depth)
+		let q = if depth > ctx.stop_mo_at_depth {
+			-nnsearch_mo(ctx, enemy, me, -beta, -alpha, depth - 1, &mut child_pv)
 		} else {
-			-nnsearch_nomo(model,enemy, me, -beta, -alpha, depth - 1)
+			-nnsearch_nomo(ctx, enemy, me, -beta, -alpha, depth - 1, &mut child_pv)
 		};
 		
 		if q >= beta {
+			pv.set(mov, &child_pv);
+			nnsearch_store(ctx, hash, depth, q, alpha_orig, beta, mov);
 			return q;
 		}
 		
 		if q > best_score {
 			best_score = q;
+			best_move = mov;
+			pv.set(mov, &child_pv);
 			if q > alpha {
 				alpha = q;
 			}
@@ -212,6 +433,122 @@ fn nnsearch_mo(model: &CModule, me: u64, enemy: u64, mut alpha: i32, beta: i32,
 		
 	}
 	
+	nnsearch_store(ctx, hash, depth, best_score, alpha_orig, beta, best_move);
+	
 	return best_score;
 	
 }
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	use crate::transposition_table::{TableClient, TranspositionTable};
+	use crate::evaluator::{DiskMobilityEvaluator, RandomEvaluator};
+	use crate::othello_board::{empty_disks, next_idx_move};
+	use std::time::Duration;
+	
+	const DEPTH: i8 = 5;
+	const TT_MB: usize = 4;
+	
+	/// Positions of random games kept at each number of empties
+	const POSITIONS_PER_EMPTIES: usize = 3;
+	
+	/// Midgame positions with moves from random games, a few at each of several numbers of empties
+	fn test_positions() -> Vec<(u64, u64)> {
+		
+		let mut state: u64 = 0x2545F4914F6CDD1D;
+		let mut positions = vec![];
+		
+		for empties in [56, 50, 44, 38, 32, 26] {
+			let mut found = 0;
+			while found < POSITIONS_PER_EMPTIES {
+				
+				let (mut me, mut enemy) = (0x0000000810000000u64, 0x0000001008000000u64);
+				while empty_disks(me, enemy) > empties && !game_over(me, enemy) {
+					let mut moves = generate_moves(me, enemy);
+					if moves != 0 {
+						state ^= state << 13;
+						state ^= state >> 7;
+						state ^= state << 17;
+						for _ in 0..state % moves.count_ones() as u64 {
+							next_idx_move(&mut moves);
+						}
+						(me, enemy) = make_move(1 << next_idx_move(&mut moves), me, enemy);
+					}
+					(me, enemy) = (enemy, me);
+				}
+				
+				if empty_disks(me, enemy) == empties && generate_moves(me, enemy) != 0 {
+					positions.push((me, enemy));
+					found += 1;
+				}
+				
+			}
+		}
+		
+		positions
+		
+	}
+	
+	#[test]
+	fn multipv_top_move_matches_search() {
+		let eval = RandomEvaluator::new(1);
+		for (me, enemy) in test_positions() {
+			let result = nnsearch_root(&eval, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -640000, 640000, DEPTH);
+			let top = &nnsearch_root_multipv(&eval, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, DEPTH, 1)[0];
+			assert_eq!((top.best_move, top.score), (result.best_move, result.score), "{:#018x} {:#018x}", me, enemy);
+		}
+	}
+	
+	#[test]
+	fn multipv_scores_every_move() {
+		let eval = DiskMobilityEvaluator;
+		for (me, enemy) in test_positions() {
+			let results = nnsearch_root_multipv(&eval, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, DEPTH, 0);
+			let result = nnsearch_root(&eval, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -640000, 640000, DEPTH);
+			assert_eq!(results.len() as u32, generate_moves(me, enemy).count_ones());
+			assert!(results.windows(2).all(|pair| pair[0].score >= pair[1].score));
+			assert_eq!(results[0].score, result.score);
+		}
+	}
+	
+	#[test]
+	fn iterative_matches_fixed_depth() {
+		let random = RandomEvaluator::new(2);
+		let far = Instant::now() + Duration::from_secs(3600);
+		for (me, enemy) in test_positions() {
+			
+			let (result, depth) = nnsearch_root_iterative(&random, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, DEPTH, far, far);
+			let fixed = nnsearch_root(&random, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -640000, 640000, DEPTH);
+			assert_eq!(depth, DEPTH);
+			assert_eq!((result.best_move, result.score), (fixed.best_move, fixed.score), "{:#018x} {:#018x}", me, enemy);
+			
+			// evals with ties can order equal moves differently, but not change the score
+			let (result, _) = nnsearch_root_iterative(&DiskMobilityEvaluator, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, DEPTH, far, far);
+			let fixed = nnsearch_root(&DiskMobilityEvaluator, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -640000, 640000, DEPTH);
+			assert_eq!(result.score, fixed.score);
+			
+		}
+	}
+	
+	#[test]
+	fn warm_table_matches_cold() {
+		let eval = RandomEvaluator::new(3);
+		let warm_table = TranspositionTable::with_size_mb(TT_MB);
+		let warm = warm_table.for_client(TableClient::take_slot());
+		for (me, enemy) in test_positions() {
+			
+			// fill the shared table with shallower and equal depth searches of the position
+			nnsearch_root(&eval, warm, me, enemy, -640000, 640000, DEPTH - 2);
+			let first = nnsearch_root(&eval, warm, me, enemy, -640000, 640000, DEPTH);
+			let again = nnsearch_root(&eval, warm, me, enemy, -640000, 640000, DEPTH);
+			let cold = nnsearch_root(&eval, TranspositionTable::with_size_mb(TT_MB).for_client(TableClient::take_slot()), me, enemy, -640000, 640000, DEPTH);
+			
+			assert_eq!((first.best_move, first.score), (cold.best_move, cold.score), "{:#018x} {:#018x}", me, enemy);
+			assert_eq!((again.best_move, again.score), (cold.best_move, cold.score), "{:#018x} {:#018x}", me, enemy);
+			
+		}
+	}
+	
+}
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::fs::File;
+use std::io::ErrorKind;
 use byteorder::{LittleEndian, ReadBytesExt};
 use xz2::read::XzDecoder;
 use crate::othello_symmetry::{sym_inverse_loc, sym_min_board};
@@ -40,25 +41,27 @@ pub fn search_book(book: &OthelloBook, me: u64, enemy: u64) -> Option<(u8, i16)>
 }
 
 /// Read the opening book from a file
-pub fn read_book(file_name: &str) -> OthelloBook {
+/// Fails if the file can't be opened or read, or ends in the middle of an entry
+pub fn read_book(file_name: &str) -> std::io::Result<OthelloBook> {
 	
 	let mut book: OthelloBook = HashMap::new();
 	
-	let file = File::open(&file_name).expect("Error opening book file");
+	let file = File::open(file_name)?;
 	let mut decompressor = XzDecoder::new(file);
 	
 	loop {
 		
-		// try reading an entry
+		// try reading an entry, the file ends between entries
 		let me = match decompressor.read_u64::<LittleEndian>() {
 			Ok(res) => res,
-			Err(_) => break
+			Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+			Err(e) => return Err(e)
 		};
 		
 		// if the first read succeeded, there must be another 8 + 1 + 1 bytes
-		let enemy = decompressor.read_u64::<LittleEndian>().unwrap();
-		let best_move = decompressor.read_u8().unwrap();
-		let eval = decompressor.read_i8().unwrap();
+		let enemy = decompressor.read_u64::<LittleEndian>()?;
+		let best_move = decompressor.read_u8()?;
+		let eval = decompressor.read_i8()?;
 		
 		// add the state to the book
 		let key = OthelloBookKey {
@@ -74,6 +77,6 @@ pub fn read_book(file_name: &str) -> OthelloBook {
 		
 	}
 	
-	return book;
+	Ok(book)
 	
 }
@@ -1,10 +1,13 @@
 #![allow(dead_code)]
 
-use bitintr::{Blsi, Blsr};
+use std::sync::LazyLock;
+#[cfg(target_arch = "x86_64")]
 use crate::board_consts;
 
 const A_FILE: u64 = 0x0101010101010101;
 const H_FILE: u64 = 0x8080808080808080;
+const RANK_1: u64 = 0x00000000000000FF;
+const RANK_8: u64 = 0xFF00000000000000;
 
 #[inline(always)]
 fn shift_n(bb: u64) -> u64 {
@@ -46,26 +49,123 @@ fn shift_sw(bb: u64) -> u64 {
 	return shift_s(shift_w(bb));
 }
 
-/// Use neural network to evaluate a state
+/// Implementations of the move generator and flipper, the fastest one the CPU supports is used
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BoardKernel {
+	/// pext/pdep lookup flipper
+	Bmi2,
+	/// Kogge-Stone flipper vectorized with AVX2
+	Avx2,
+	/// Avx2 with 2 directions of the flipper using Dumb7Fill, which is faster on Zen 1 and 2
+	Avx2Zen2,
+	/// plain Rust for any CPU
+	Scalar
+}
+
+type GenerateMovesFn = unsafe fn(u64, u64) -> u64;
+type MakeMoveFn = unsafe fn(u64, u64, u64) -> (u64, u64);
+
+/// Move generator and flipper of a kernel
+/// They may use CPU features that have to be detected first, so they are unsafe to call
+struct BoardFunctions {
+	kernel: BoardKernel,
+	generate_moves: GenerateMovesFn,
+	make_move: MakeMoveFn
+}
+
+/// The functions of the detected kernel, picked on the first move generated
+static BOARD_FUNCTIONS: LazyLock<BoardFunctions> = LazyLock::new(|| BoardKernel::detect().functions());
+
+impl BoardKernel {
+	
+	/// Fastest kernel the CPU supports
+	/// BMI2 is skipped on AMD family 17h (Zen 1 and 2), where pext and pdep are microcoded and slow
+	pub fn detect() -> Self {
+		#[cfg(target_arch = "x86_64")] {
+			let slow_pext = amd_family_17h();
+			if is_x86_feature_detected!("bmi2") && !slow_pext {
+				return BoardKernel::Bmi2;
+			}
+			if is_x86_feature_detected!("avx2") {
+				return if slow_pext { BoardKernel::Avx2Zen2 } else { BoardKernel::Avx2 };
+			}
+		}
+		BoardKernel::Scalar
+	}
+	
+	/// Kernel used by generate_moves and make_move
+	pub fn current() -> Self {
+		BOARD_FUNCTIONS.kernel
+	}
+	
+	fn functions(self) -> BoardFunctions {
+		let (generate_moves, make_move): (GenerateMovesFn, MakeMoveFn) = match self {
+			#[cfg(target_arch = "x86_64")]
+			BoardKernel::Bmi2 => (generate_moves_bmi2, make_move_bmi2),
+			#[cfg(target_arch = "x86_64")]
+			BoardKernel::Avx2 => (generate_moves_avx2, make_move_avx2),
+			#[cfg(target_arch = "x86_64")]
+			BoardKernel::Avx2Zen2 => (generate_moves_avx2, make_move_avx2_zen2),
+			_ => (generate_moves_kogge_stone, make_move_kogge_stone::<false>)
+		};
+		BoardFunctions { kernel: self, generate_moves, make_move }
+	}
+	
+}
+
+/// True on AMD CPUs of family 17h (Zen, Zen+ and Zen 2)
+#[cfg(target_arch = "x86_64")]
+fn amd_family_17h() -> bool {
+	use std::arch::x86_64::__cpuid;
+	// the vendor string is in ebx, edx, ecx of leaf 0
+	let vendor = __cpuid(0);
+	let amd = [vendor.ebx, vendor.edx, vendor.ecx] == [u32::from_le_bytes(*b"Auth"), u32::from_le_bytes(*b"enti"), u32::from_le_bytes(*b"cAMD")];
+	// family is the base family plus the extended family
+	let signature = __cpuid(1).eax;
+	let family = ((signature >> 8) & 0xF) + ((signature >> 20) & 0xFF);
+	amd && family == 0x17
+}
+
+/// Bitboard of the legal moves of `bb_self`
 #[inline(always)]
 pub fn generate_moves(bb_self: u64, bb_enemy: u64) -> u64 {
-	if cfg!(feature = "bmi2") {
-		generate_moves_bmi2(bb_self, bb_enemy)
-	} else {
-		generate_moves_avx(bb_self, bb_enemy)
-	}
+	// the kernel was detected on this CPU
+	unsafe { (BOARD_FUNCTIONS.generate_moves)(bb_self, bb_enemy) }
 }
 
+/// Plays `mov` (1 << mov_idx) for `bb_self`, returns the new (bb_self, bb_enemy)
 #[inline(always)]
 pub fn make_move(mov: u64, bb_self: u64, bb_enemy: u64) -> (u64, u64) {
-	if cfg!(feature = "bmi2") {
-		make_move_bmi2(mov, bb_self, bb_enemy)
-	} else {
-		make_move_avx(mov, bb_self, bb_enemy)
-	}
+	unsafe { (BOARD_FUNCTIONS.make_move)(mov, bb_self, bb_enemy) }
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi1,bmi2")]
+unsafe fn generate_moves_bmi2(bb_self: u64, bb_enemy: u64) -> u64 {
+	generate_moves_kogge_stone(bb_self, bb_enemy)
 }
 
-fn generate_moves_bmi2(bb_self: u64, bb_enemy: u64) -> u64 {
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn generate_moves_avx2(bb_self: u64, bb_enemy: u64) -> u64 {
+	generate_moves_dumb7(bb_self, bb_enemy)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn make_move_avx2(mov: u64, bb_self: u64, bb_enemy: u64) -> (u64, u64) {
+	make_move_kogge_stone::<false>(mov, bb_self, bb_enemy)
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn make_move_avx2_zen2(mov: u64, bb_self: u64, bb_enemy: u64) -> (u64, u64) {
+	make_move_kogge_stone::<true>(mov, bb_self, bb_enemy)
+}
+
+/// Move generator with Kogge-Stone fills, 3 steps per direction
+#[inline(always)]
+fn generate_moves_kogge_stone(bb_self: u64, bb_enemy: u64) -> u64 {
 	
 	let mut flips = gen(bb_self, bb_enemy, -9);
 	flips |= gen(bb_self, bb_enemy, -8);
@@ -80,6 +180,7 @@ fn generate_moves_bmi2(bb_self: u64, bb_enemy: u64) -> u64 {
 	
 }
 
+#[inline(always)]
 fn gen(bb_self: u64, bb_enemy: u64, dir: isize) -> u64 {
 	// https://gitlab.com/rust-othello/8x8-othello
 	//rotate might be faster on AVX-512
@@ -109,7 +210,9 @@ fn gen(bb_self: u64, bb_enemy: u64, dir: isize) -> u64 {
 	shift(x ^ bb_self, dir)
 }
 
-fn generate_moves_avx(bb_self: u64, bb_enemy: u64) -> u64 {
+/// Move generator with Dumb7Fill, 6 steps per direction which vectorize well
+#[inline(always)]
+fn generate_moves_dumb7(bb_self: u64, bb_enemy: u64) -> u64 {
 	
 	let mut moves: u64 = 0;
 	let mut captured: u64;
@@ -170,12 +273,9 @@ fn generate_moves_avx(bb_self: u64, bb_enemy: u64) -> u64 {
 /// Removes it as a move from moves
 #[inline(always)]
 pub fn next_bit_move(moves: &mut u64) -> u64 {
-	// the following 3 lines work without bitintr, but are slightly slower
-	// let i = moves.trailing_zeros();
-	// let mov = 1 << i;
-	// *moves &= !mov;
-	let mov = moves.blsi();
-	*moves = moves.blsr();
+	// compiles to blsi and blsr on CPUs with BMI1
+	let mov = *moves & moves.wrapping_neg();
+	*moves &= moves.wrapping_sub(1);
 	return mov;
 }
 
@@ -183,12 +283,9 @@ pub fn next_bit_move(moves: &mut u64) -> u64 {
 /// Removes it as a move from moves
 #[inline(always)]
 pub fn next_idx_move(moves: &mut u64) -> u8 {
-	// the following 3 lines work without bitintr, but are slightly slower
-	// let i = moves.trailing_zeros();
-	// let mov = 1 << i;
-	// *moves &= !mov;
+	// compiles to blsr on CPUs with BMI1
 	let i = moves.trailing_zeros() as u8;
-	*moves = moves.blsr();
+	*moves &= moves.wrapping_sub(1);
 	return i;
 }
 
@@ -197,30 +294,36 @@ pub fn number_of_moves(bb_self: u64, bb_enemy: u64) -> u8 {
 	return generate_moves(bb_self, bb_enemy).count_ones() as u8;
 }
 
-fn make_move_bmi2(mov: u64, bb_self: u64, bb_enemy: u64) -> (u64, u64) {
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "bmi1,bmi2")]
+unsafe fn make_move_bmi2(mov: u64, bb_self: u64, bb_enemy: u64) -> (u64, u64) {
 	// https://gitlab.com/rust-othello/8x8-othello
+	use std::arch::x86_64::{_pdep_u64, _pext_u64};
+	use board_consts::*;
 	let place = mov.trailing_zeros() as usize;
 	let diff = (0..4)
-		.map(|i| unsafe {
-			use bitintr::*;
-			use board_consts::*;
-			u64::from(*RESULT.get_unchecked(
+		.map(|i| {
+			let result = u64::from(*RESULT.get_unchecked(
 				INDEX.get_unchecked(place)[i] as usize * 32
-					+ bb_self.pext(MASK.get_unchecked(place)[i][0]) as usize * 64
-					+ bb_enemy.pext(MASK.get_unchecked(place)[i][1]) as usize,
-			))
-			.pdep(MASK.get_unchecked(place)[i][1])
+					+ _pext_u64(bb_self, MASK.get_unchecked(place)[i][0]) as usize * 64
+					+ _pext_u64(bb_enemy, MASK.get_unchecked(place)[i][1]) as usize,
+			));
+			_pdep_u64(result, MASK.get_unchecked(place)[i][1])
 		})
 		.fold(0, core::ops::BitOr::bitor);
 	return (bb_self ^ diff ^ mov, bb_enemy ^ diff);
 }
 
-pub fn make_move_avx(mov: u64, mut bb_self: u64, mut bb_enemy: u64) -> (u64, u64) {
-	make_move_inplace(mov, &mut bb_self, &mut bb_enemy);
+/// Flipper with Kogge-Stone fills, which doesn't need any CPU features
+/// With DUMB7_DIAGONALS, 2 directions use Dumb7Fill instead (the Avx2Zen2 kernel)
+#[inline(always)]
+fn make_move_kogge_stone<const DUMB7_DIAGONALS: bool>(mov: u64, mut bb_self: u64, mut bb_enemy: u64) -> (u64, u64) {
+	make_move_inplace::<DUMB7_DIAGONALS>(mov, &mut bb_self, &mut bb_enemy);
 	return (bb_self, bb_enemy);
 }
 
-pub fn make_move_inplace(mov: u64, bb_self: &mut u64, bb_enemy: &mut u64) {
+#[inline(always)]
+fn make_move_inplace<const DUMB7_DIAGONALS: bool>(mov: u64, bb_self: &mut u64, bb_enemy: &mut u64) {
 	
 	// TODO: Optimize. in endgame search, 46% of the time is spent here
 	// for each of 64 disks, have 8 direction masks. use pext to get ray
@@ -233,7 +336,7 @@ pub fn make_move_inplace(mov: u64, bb_self: &mut u64, bb_enemy: &mut u64) {
 	// Kogge-Stone algorithm. Modified for Othello from
 	// https://www.chessprogramming.org/Kogge-Stone_Algorithm#Occluded_Fill
 	
-	// 2 directions (NE, SW) use the Dumb7Fill algorithm in tune_zen2 mode
+	// 2 directions (NE, SW) use the Dumb7Fill algorithm with DUMB7_DIAGONALS, for zen2
 	// https://www.chessprogramming.org/Dumb7Fill#OccludedFill
 	// Kogge-Stone is vectorized with AVX2, which uses few, but slow, instructions.
 	// Processing 2 directions Dumb7fill, allows the CPU to parallelize AVX and normal registers.
@@ -313,7 +416,7 @@ pub fn make_move_inplace(mov: u64, bb_self: &mut u64, bb_enemy: &mut u64) {
 	
 	
 	// NORTH EAST
-	if cfg!(feature = "tune_zen2") {
+	if DUMB7_DIAGONALS {
 		captured = shift_ne(mov) & *bb_enemy;
 		for _ in 0..5 {
 			captured |= shift_ne(captured) & *bb_enemy;
@@ -367,7 +470,7 @@ pub fn make_move_inplace(mov: u64, bb_self: &mut u64, bb_enemy: &mut u64) {
 	}
 	
 	// SOUTH WEST
-	if cfg!(feature = "tune_zen2") {
+	if DUMB7_DIAGONALS {
 		captured = shift_sw(mov) & *bb_enemy;
 		for _ in 0..5 {
 			captured |= shift_sw(captured) & *bb_enemy;
@@ -391,6 +494,51 @@ pub fn make_move_inplace(mov: u64, bb_self: &mut u64, bb_enemy: &mut u64) {
 	
 }
 
+/// Squares whose line along a direction has no empty squares
+/// `shift_a` and `shift_b` move one square each way along the direction
+#[inline(always)]
+fn full_lines(filled: u64, shift_a: fn(u64) -> u64, shift_b: fn(u64) -> u64) -> u64 {
+	// spread the empty squares along their lines
+	let mut empty = !filled;
+	for _ in 0..7 {
+		empty |= shift_a(empty) | shift_b(empty);
+	}
+	return !empty;
+}
+
+/// Finds disks of `bb_self` that can never be flipped
+/// A disk is stable if along each of the 4 lines through it, the line is full,
+/// or a neighbor on the line is off the board or a stable disk of the same color.
+/// This finds most stable disks, but not all of them
+pub fn stable_disks(bb_self: u64, bb_enemy: u64) -> u64 {
+	
+	let filled = bb_self | bb_enemy;
+	let edges = A_FILE | H_FILE | RANK_1 | RANK_8;
+	
+	let full_h = full_lines(filled, shift_e, shift_w) | A_FILE | H_FILE;
+	let full_v = full_lines(filled, shift_n, shift_s) | RANK_1 | RANK_8;
+	let full_d9 = full_lines(filled, shift_ne, shift_sw) | edges;
+	let full_d7 = full_lines(filled, shift_nw, shift_se) | edges;
+	
+	// grow the stable disks from the edges and full lines until nothing changes
+	let mut stable = 0u64;
+	loop {
+		
+		let new_stable = bb_self
+			& (full_h | shift_e(stable) | shift_w(stable))
+			& (full_v | shift_n(stable) | shift_s(stable))
+			& (full_d9 | shift_ne(stable) | shift_sw(stable))
+			& (full_d7 | shift_nw(stable) | shift_se(stable));
+		
+		if new_stable == stable {
+			return stable;
+		}
+		stable = new_stable;
+		
+	}
+	
+}
+
 #[inline(always)]
 pub fn game_over(bb_p1: u64, bb_p2: u64) -> bool {
 	return (bb_p1 | bb_p2 == u64::MAX) ||
@@ -461,3 +609,375 @@ pub fn to_bit_move_vec(mut moves: u64) -> Vec<u64> {
 	return vec;
 	
 }
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	use crate::othello_symmetry::sym_all_boards;
+	use std::time::Instant;
+	
+	/// Random games played for the differential tests, about 60 positions each
+	const RANDOM_GAMES: usize = 20_000;
+	
+	const START_SELF: u64 = 0x0000000810000000;
+	const START_ENEMY: u64 = 0x0000001008000000;
+	
+	/// Random games played from each position to check that its stable disks never flip
+	const CONTINUATIONS: usize = 4;
+	
+	/// Perft counts from the starting position, passes count as a move
+	const PERFT: [u64; 11] = [4, 12, 56, 244, 1396, 8200, 55092, 390216, 3005288, 24571284, 212258800];
+	
+	const DIRECTIONS: [(i32, i32); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+	
+	/// Disks flipped by playing the square `idx`, walking each direction a square at a time
+	fn reference_flips(idx: u8, bb_self: u64, bb_enemy: u64) -> u64 {
+		let (x, y) = ((idx % 8) as i32, (idx / 8) as i32);
+		let mut flips = 0;
+		for (dx, dy) in DIRECTIONS {
+			let mut line = 0;
+			let (mut cx, mut cy) = (x + dx, y + dy);
+			while (0..8).contains(&cx) && (0..8).contains(&cy) {
+				let bit = 1u64 << (cy * 8 + cx);
+				if bb_enemy & bit == 0 {
+					// the line is only flipped if it ends in a disk of our own
+					if bb_self & bit != 0 {
+						flips |= line;
+					}
+					break;
+				}
+				line |= bit;
+				cx += dx;
+				cy += dy;
+			}
+		}
+		flips
+	}
+	
+	/// Empty squares that flip at least one disk
+	fn reference_moves(bb_self: u64, bb_enemy: u64) -> u64 {
+		(0..64)
+			.filter(|idx| (bb_self | bb_enemy) & (1 << idx) == 0 && reference_flips(*idx, bb_self, bb_enemy) != 0)
+			.fold(0, |moves, idx| moves | 1 << idx)
+	}
+	
+	/// Kernels this CPU can run, they can only be tested where they are supported
+	fn supported_kernels() -> Vec<BoardKernel> {
+		let mut kernels = vec![BoardKernel::Scalar];
+		#[cfg(target_arch = "x86_64")] {
+			if is_x86_feature_detected!("bmi1") && is_x86_feature_detected!("bmi2") {
+				kernels.push(BoardKernel::Bmi2);
+			}
+			if is_x86_feature_detected!("avx2") {
+				kernels.push(BoardKernel::Avx2);
+				kernels.push(BoardKernel::Avx2Zen2);
+			}
+		}
+		kernels
+	}
+	
+	/// xorshift64, so the positions are the same in every run
+	fn next_random(state: &mut u64) -> u64 {
+		*state ^= *state << 13;
+		*state ^= *state >> 7;
+		*state ^= *state << 17;
+		*state
+	}
+	
+	/// Every position (self, enemy) of `games` random games, including the final ones
+	/// Moves are played with the reference, so the positions don't depend on the kernels under test
+	fn random_positions(games: usize, seed: u64) -> Vec<(u64, u64)> {
+		
+		let mut state = seed;
+		let mut positions = Vec::with_capacity(games * 64);
+		
+		for _ in 0..games {
+			
+			let (mut bb_self, mut bb_enemy) = (START_SELF, START_ENEMY);
+			let mut passed = false;
+			
+			loop {
+				
+				positions.push((bb_self, bb_enemy));
+				
+				let moves = reference_moves(bb_self, bb_enemy);
+				if moves == 0 {
+					if passed {
+						break;
+					}
+					passed = true;
+					(bb_self, bb_enemy) = (bb_enemy, bb_self);
+					continue;
+				}
+				passed = false;
+				
+				let moves = to_idx_move_vec(moves);
+				let idx = moves[(next_random(&mut state) % moves.len() as u64) as usize];
+				let flips = reference_flips(idx, bb_self, bb_enemy);
+				(bb_self, bb_enemy) = (bb_enemy & !flips, bb_self | flips | 1 << idx);
+				
+			}
+			
+		}
+		
+		positions
+		
+	}
+	
+	/// Counts the leaves `depth` moves from the position, a pass is a move and a finished game is a leaf
+	fn perft(functions: &BoardFunctions, bb_self: u64, bb_enemy: u64, depth: u8, passed: bool) -> u64 {
+		
+		let mut moves = unsafe { (functions.generate_moves)(bb_self, bb_enemy) };
+		
+		if moves == 0 {
+			if passed || depth == 1 {
+				return 1;
+			}
+			return perft(functions, bb_enemy, bb_self, depth - 1, true);
+		}
+		
+		if depth == 1 {
+			return moves.count_ones() as u64;
+		}
+		
+		let mut leaves = 0;
+		while moves != 0 {
+			let mov = next_bit_move(&mut moves);
+			let (new_self, new_enemy) = unsafe { (functions.make_move)(mov, bb_self, bb_enemy) };
+			leaves += perft(functions, new_enemy, new_self, depth - 1, false);
+		}
+		leaves
+		
+	}
+	
+	/// perft through generate_moves and make_move, which look up the kernel on every call
+	fn perft_dispatched(bb_self: u64, bb_enemy: u64, depth: u8, passed: bool) -> u64 {
+		
+		let mut moves = generate_moves(bb_self, bb_enemy);
+		
+		if moves == 0 {
+			if passed || depth == 1 {
+				return 1;
+			}
+			return perft_dispatched(bb_enemy, bb_self, depth - 1, true);
+		}
+		
+		if depth == 1 {
+			return moves.count_ones() as u64;
+		}
+		
+		let mut leaves = 0;
+		while moves != 0 {
+			let mov = next_bit_move(&mut moves);
+			let (new_self, new_enemy) = make_move(mov, bb_self, bb_enemy);
+			leaves += perft_dispatched(new_enemy, new_self, depth - 1, false);
+		}
+		leaves
+		
+	}
+	
+	#[test]
+	fn reference_matches_starting_moves() {
+		// d3, c4, f5 and e6
+		assert_eq!(reference_moves(START_SELF, START_ENEMY), 1 << 19 | 1 << 26 | 1 << 37 | 1 << 44);
+		assert_eq!(reference_flips(19, START_SELF, START_ENEMY), 1 << 27);
+	}
+	
+	#[test]
+	fn kernels_match_reference() {
+		
+		let kernels: Vec<(BoardKernel, BoardFunctions)> = supported_kernels().into_iter().map(|kernel| (kernel, kernel.functions())).collect();
+		let positions = random_positions(RANDOM_GAMES, 0x2545F4914F6CDD1D);
+		assert!(positions.len() > 1_000_000);
+		
+		for (bb_self, bb_enemy) in positions {
+			
+			let moves = reference_moves(bb_self, bb_enemy);
+			for (kernel, functions) in kernels.iter() {
+				let kernel_moves = unsafe { (functions.generate_moves)(bb_self, bb_enemy) };
+				assert_eq!(kernel_moves, moves, "{:?} moves of {:#018x} {:#018x}", kernel, bb_self, bb_enemy);
+			}
+			
+			let mut remaining = moves;
+			while remaining != 0 {
+				let idx = next_idx_move(&mut remaining);
+				let flips = reference_flips(idx, bb_self, bb_enemy);
+				let expected = (bb_self | flips | 1 << idx, bb_enemy & !flips);
+				for (kernel, functions) in kernels.iter() {
+					let result = unsafe { (functions.make_move)(1 << idx, bb_self, bb_enemy) };
+					assert_eq!(result, expected, "{:?} flips of move {} in {:#018x} {:#018x}", kernel, idx, bb_self, bb_enemy);
+				}
+			}
+			
+		}
+		
+	}
+	
+	#[test]
+	fn move_properties() {
+		
+		for (bb_self, bb_enemy) in random_positions(RANDOM_GAMES / 10, 0x9E3779B97F4A7C15) {
+			
+			let moves = generate_moves(bb_self, bb_enemy);
+			let empty = !(bb_self | bb_enemy);
+			assert_eq!(moves & !empty, 0, "moves on occupied squares");
+			assert_eq!(number_of_moves(bb_self, bb_enemy) as u32, moves.count_ones());
+			assert_eq!(game_over(bb_self, bb_enemy), moves == 0 && generate_moves(bb_enemy, bb_self) == 0);
+			
+			// the moves of a symmetric board are the symmetric moves
+			let boards = sym_all_boards(bb_self, bb_enemy);
+			let symmetric_moves = sym_all_boards(moves, 0);
+			for ((sym_self, sym_enemy), (sym_moves, _)) in boards.iter().zip(symmetric_moves.iter()) {
+				assert_eq!(generate_moves(*sym_self, *sym_enemy), *sym_moves);
+			}
+			
+			let mut remaining = moves;
+			while remaining != 0 {
+				
+				let mov = next_bit_move(&mut remaining);
+				let (new_self, new_enemy) = make_move(mov, bb_self, bb_enemy);
+				let flipped = new_self & bb_enemy;
+				
+				// the move is placed, at least one disk flips, and nothing else changes
+				assert_eq!(new_self & new_enemy, 0);
+				assert_eq!(new_self, bb_self | mov | flipped);
+				assert_eq!(new_enemy, bb_enemy & !flipped);
+				assert_ne!(flipped, 0);
+				assert_eq!(empty_disks(new_self, new_enemy), empty_disks(bb_self, bb_enemy) - 1);
+				
+			}
+			
+		}
+		
+	}
+	
+	#[test]
+	fn stable_disks_hand_checked() {
+		
+		// a corner and the disks next to it on the edge, but not the enemy disk after them
+		assert_eq!(stable_disks(0b0111, 0b1000), 0b0111);
+		assert_eq!(stable_disks(0b1000, 0b0111), 0);
+		
+		// a lone disk on a corner, but not one next to an empty corner
+		assert_eq!(stable_disks(1 << 63, 1 << 62), 1 << 63);
+		assert_eq!(stable_disks(1 << 62, 1 << 61), 0);
+		
+		// a full edge is stable for both colors
+		assert_eq!(stable_disks(0x55, 0xAA), 0x55);
+		assert_eq!(stable_disks(0xAA, 0x55), 0xAA);
+		
+		// a full line away from the edges is not enough, the other lines through its disks are empty
+		assert_eq!(stable_disks(0x0F000000, 0xF0000000), 0);
+		
+		// d4 with all 4 lines through it full, the rank, the file and both diagonals
+		let d4 = 1u64 << 27;
+		let lines = 0x00000000FF000000 | 0x0808080808080808 | 0x8040201008040201 | 0x0001020408102040;
+		assert_eq!(stable_disks(d4, lines & !d4), d4);
+		
+		// but not once the diagonal has an empty square on g1
+		assert_eq!(stable_disks(d4, lines & !d4 & !(1 << 6)), 0);
+		
+		// every disk of a full board
+		assert_eq!(stable_disks(0x0F0F0F0F0F0F0F0F, 0xF0F0F0F0F0F0F0F0), 0x0F0F0F0F0F0F0F0F);
+		
+	}
+	
+	#[test]
+	fn stable_disks_never_flip() {
+		
+		let mut state: u64 = 0xD1B54A32D192ED03;
+		let mut stable_found = 0;
+		
+		for (bb_self, bb_enemy) in random_positions(RANDOM_GAMES / 100, 0x5851F42D4C957F2D) {
+			
+			let stable_self = stable_disks(bb_self, bb_enemy);
+			let stable_enemy = stable_disks(bb_enemy, bb_self);
+			assert_eq!(stable_self & !bb_self, 0);
+			assert_eq!(stable_enemy & !bb_enemy, 0);
+			stable_found += (stable_self | stable_enemy).count_ones();
+			
+			// play random games to the end, both colors keep all of their stable disks
+			for _ in 0..CONTINUATIONS {
+				
+				let (mut to_move, mut other) = ((bb_self, stable_self), (bb_enemy, stable_enemy));
+				let mut passed = false;
+				
+				loop {
+					
+					let moves = generate_moves(to_move.0, other.0);
+					if moves == 0 {
+						if passed {
+							break;
+						}
+						passed = true;
+						(to_move, other) = (other, to_move);
+						continue;
+					}
+					passed = false;
+					
+					let moves = to_idx_move_vec(moves);
+					let idx = moves[(next_random(&mut state) % moves.len() as u64) as usize];
+					let (new_self, new_enemy) = make_move(1 << idx, to_move.0, other.0);
+					assert_eq!(new_enemy & other.1, other.1, "stable disks {:#018x} flipped in {:#018x} {:#018x} by move {}", other.1, to_move.0, other.0, idx);
+					
+					(to_move, other) = ((new_enemy, other.1), (new_self, to_move.1));
+					
+				}
+				
+			}
+			
+		}
+		
+		// the positions should have plenty of stable disks, or the test checks nothing
+		assert!(stable_found > 10_000, "only {} stable disks", stable_found);
+		
+	}
+	
+	#[test]
+	fn perft_all_kernels() {
+		for kernel in supported_kernels() {
+			let functions = kernel.functions();
+			for (depth, expected) in PERFT.iter().take(9).enumerate() {
+				assert_eq!(perft(&functions, START_SELF, START_ENEMY, depth as u8 + 1, false), *expected, "{:?} perft {}", kernel, depth + 1);
+			}
+		}
+	}
+	
+	#[test]
+	fn perft_10() {
+		assert_eq!(perft(&BOARD_FUNCTIONS, START_SELF, START_ENEMY, 10, false), PERFT[9]);
+	}
+	
+	#[test]
+	#[ignore = "slow, run with --ignored"]
+	fn perft_11() {
+		assert_eq!(perft(&BOARD_FUNCTIONS, START_SELF, START_ENEMY, 11, false), PERFT[10]);
+	}
+	
+	/// Leaves per second of perft 11 through the lookup on every call, and through the kernel's functions resolved once
+	/// Both call through a function pointer, the lookup is an atomic load that stays in the cache
+	#[test]
+	#[ignore = "benchmark, run with --ignored --nocapture to print the rates"]
+	fn dispatch_rate() {
+		
+		let resolved = BoardKernel::current().functions();
+		
+		for _ in 0..3 {
+			
+			let start = Instant::now();
+			assert_eq!(perft_dispatched(START_SELF, START_ENEMY, 11, false), PERFT[10]);
+			let dispatched = start.elapsed();
+			
+			let start = Instant::now();
+			assert_eq!(perft(&resolved, START_SELF, START_ENEMY, 11, false), PERFT[10]);
+			let once = start.elapsed();
+			
+			println!("{:?}: dispatched {:.1} Mleaves/s, resolved once {:.1} Mleaves/s", resolved.kernel,
+				PERFT[10] as f64 / dispatched.as_secs_f64() / 1e6, PERFT[10] as f64 / once.as_secs_f64() / 1e6);
+			
+		}
+		
+	}
+	
+}
@@ -167,3 +167,76 @@ fn sym_apply_to_bb(transform: &[u8; 64], bb: u64) -> u64 {
 	return new_board;
 	
 }
+
+/// Finds the same minimum symmetry as sym_min_board, but with bit operations,
+/// so it is fast enough to run on every evaluated board
+/// Returns (me, enemy)
+#[inline(always)]
+pub fn sym_canonical_board(me: u64, enemy: u64) -> (u64, u64) {
+	
+	let mut min = (me, enemy);
+	let mut board = (me, enemy);
+	
+	// 4 reflections of the board, then the same 4 of the transposed board
+	for i in 0..8 {
+		board = match i {
+			4 => (bb_flip_diagonal(me), bb_flip_diagonal(enemy)),
+			1 | 3 | 5 | 7 => (bb_flip_vertical(board.0), bb_flip_vertical(board.1)),
+			_ => (bb_mirror_horizontal(board.0), bb_mirror_horizontal(board.1))
+		};
+		min = min.min(board);
+	}
+	
+	min
+	
+}
+
+/// The 8 symmetries of a board as (me, enemy)
+/// The first 1, 2 and 4 are groups (identity, 180 degree rotation, then the diagonal flips),
+/// so averaging over the first n is the same for every board in those symmetries
+#[inline(always)]
+pub fn sym_all_boards(me: u64, enemy: u64) -> [(u64, u64); 8] {
+	
+	let rotate_180 = |bb: u64| bb_flip_vertical(bb_mirror_horizontal(bb));
+	let (t_me, t_enemy) = (bb_flip_diagonal(me), bb_flip_diagonal(enemy));
+	
+	[
+		(me, enemy),
+		(rotate_180(me), rotate_180(enemy)),
+		(t_me, t_enemy),
+		(rotate_180(t_me), rotate_180(t_enemy)),
+		(bb_mirror_horizontal(me), bb_mirror_horizontal(enemy)),
+		(bb_flip_vertical(me), bb_flip_vertical(enemy)),
+		(bb_mirror_horizontal(t_me), bb_mirror_horizontal(t_enemy)),
+		(bb_flip_vertical(t_me), bb_flip_vertical(t_enemy))
+	]
+	
+}
+
+/// Swaps the rows of a bitboard (rank 1 <-> rank 8)
+#[inline(always)]
+fn bb_flip_vertical(bb: u64) -> u64 {
+	bb.swap_bytes()
+}
+
+/// Swaps the columns of a bitboard (file a <-> file h)
+/// https://www.chessprogramming.org/Flipping_Mirroring_and_Rotating#MirrorHorizontally
+#[inline(always)]
+fn bb_mirror_horizontal(bb: u64) -> u64 {
+	let bb = ((bb >> 1) & 0x5555555555555555) | ((bb & 0x5555555555555555) << 1);
+	let bb = ((bb >> 2) & 0x3333333333333333) | ((bb & 0x3333333333333333) << 2);
+	((bb >> 4) & 0x0F0F0F0F0F0F0F0F) | ((bb & 0x0F0F0F0F0F0F0F0F) << 4)
+}
+
+/// Flips a bitboard about the a1-h8 diagonal
+/// https://www.chessprogramming.org/Flipping_Mirroring_and_Rotating#FlipabouttheDiagonal
+#[inline(always)]
+fn bb_flip_diagonal(mut bb: u64) -> u64 {
+	let t = 0x0F0F0F0F00000000 & (bb ^ (bb << 28));
+	bb ^= t ^ (t >> 28);
+	let t = 0x3333000033330000 & (bb ^ (bb << 14));
+	bb ^= t ^ (t >> 14);
+	let t = 0x5500550055005500 & (bb ^ (bb << 7));
+	bb ^= t ^ (t >> 7);
+	bb
+}
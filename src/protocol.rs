@@ -0,0 +1,186 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::Duration;
+use byteorder::{NetworkEndian, ReadBytesExt, WriteBytesExt};
+
+/// Sent by v2 clients in place of the first 8 bytes of a legacy request, followed by a u16 version
+/// Legacy requests start with the `me` bitboard, which is never "OTHELLRS" in a real game
+pub const PROT_MAGIC: [u8; 8] = *b"OTHELLRS";
+
+/// Highest protocol version supported by the server
+pub const PROT_VERSION: u16 = 4;
+
+/// Size of a legacy request: me: u64, enemy: u64, time: u16, params: u16
+pub const LEGACY_REQUEST_SIZE: usize = 8 + 8 + 2 + 2;
+
+/// Size of a timed search request: a legacy request followed by move_time: u32 (ms)
+pub const TIMED_REQUEST_SIZE: usize = LEGACY_REQUEST_SIZE + 4;
+
+/// Max length of a frame, larger frames are rejected
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+/// Max time to wait for the rest of a handshake, legacy request or v2 frame once it has started
+/// Between v2 requests the server waits up to the idle timeout instead (see EngineConfig)
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Max time to wait for a client to accept a reply
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request a search, payload is a legacy request (!QQHH)
+pub const MSG_SEARCH: u8 = 0x01;
+/// Check the connection, empty payload
+pub const MSG_PING: u8 = 0x02;
+/// Close the connection, empty payload
+pub const MSG_CLOSE: u8 = 0x03;
+/// Request an exact score for every move, payload is a legacy request (!QQHH) and the number of moves to score (!B, 0 = all)
+pub const MSG_ANALYZE: u8 = 0x04;
+/// Request a search within a time budget, payload is a legacy request and the move time in ms (!QQHHI)
+/// The midgame search deepens up to the requested depth until the move time runs out, the reply is MSG_SEARCH_REPLY
+pub const MSG_SEARCH_TIMED: u8 = 0x05;
+
+/// Reply to MSG_SEARCH, payload is a legacy reply (!Bh), followed by the score kind (!B) from v3,
+/// the confidence in % (!B) if the kind is selective, and the engine status (!B) from v4
+pub const MSG_SEARCH_REPLY: u8 = 0x81;
+/// Reply to MSG_PING, empty payload, the engine status (!B) from v4
+pub const MSG_PONG: u8 = 0x82;
+/// Reply to MSG_ANALYZE, payload is the number of moves (!B), the engine status (!B) from v4,
+/// then an extended reply per move, best first
+pub const MSG_ANALYZE_REPLY: u8 = 0x84;
+/// Reply to a request that couldn't be handled, payload is an error code (!B)
+pub const MSG_ERROR: u8 = 0xFF;
+
+/// Error code for a message type the server doesn't know
+pub const ERR_UNKNOWN_MESSAGE: u8 = 1;
+/// Error code for a payload with the wrong size or contents
+pub const ERR_MALFORMED: u8 = 2;
+/// Error code for a position that can't occur in a game (overlapping disks or empty center squares)
+pub const ERR_INVALID_POSITION: u8 = 3;
+
+/// Engine status bit, the neural network failed to load and midgame searches use the classic evaluator
+pub const STATUS_NO_MODEL: u8 = 0x01;
+/// Engine status bit, the opening book failed to load and no book moves are played
+pub const STATUS_NO_BOOK: u8 = 0x02;
+
+/// Best move of a legacy reply to a request that couldn't be handled, the eval is the error code
+pub const LEGACY_ERROR_MOVE: u8 = 0xFF;
+
+/// Reasons a client connection fails
+#[derive(Debug)]
+pub enum ServerError {
+	/// the client closed the connection in the middle of a message
+	Disconnected,
+	/// the client didn't send or accept data within the timeouts
+	Timeout,
+	/// the request has the wrong size or contents
+	Malformed(String),
+	/// the position of the request can't occur in a game
+	InvalidPosition(String),
+	/// any other socket error
+	Io(Error)
+}
+
+impl ServerError {
+	
+	/// Error code sent to the client, None if no reply can be sent
+	pub fn code(&self) -> Option<u8> {
+		match self {
+			ServerError::Malformed(_) => Some(ERR_MALFORMED),
+			ServerError::InvalidPosition(_) => Some(ERR_INVALID_POSITION),
+			_ => None
+		}
+	}
+	
+}
+
+impl Display for ServerError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match self {
+			ServerError::Disconnected => write!(f, "client disconnected"),
+			ServerError::Timeout => write!(f, "client timed out"),
+			ServerError::Malformed(msg) => write!(f, "malformed request: {}", msg),
+			ServerError::InvalidPosition(msg) => write!(f, "invalid position: {}", msg),
+			ServerError::Io(e) => write!(f, "{}", e)
+		}
+	}
+}
+
+impl From<Error> for ServerError {
+	fn from(e: Error) -> Self {
+		match e.kind() {
+			ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => ServerError::Disconnected,
+			// unix reports a timeout as WouldBlock, windows as TimedOut
+			ErrorKind::WouldBlock | ErrorKind::TimedOut => ServerError::Timeout,
+			ErrorKind::InvalidData => ServerError::Malformed(e.to_string()),
+			_ => ServerError::Io(e)
+		}
+	}
+}
+
+/// A single message of the v2 protocol
+/// Framed as length: u32, request_id: u32, msg_type: u8, payload
+/// length counts the bytes after the length field
+pub struct Frame {
+	/// id chosen by the client, replies carry the id of the request
+	pub request_id: u32,
+	/// one of the MSG_* constants
+	pub msg_type: u8,
+	pub payload: Vec<u8>
+}
+
+/// Reads the rest of a v2 handshake (the version), after the magic has been read
+/// Replies with the magic and the version used for the connection
+/// Returns the negotiated version
+pub fn accept_handshake<S: Read + Write>(stream: &mut S) -> std::io::Result<u16> {
+	
+	let client_version = stream.read_u16::<NetworkEndian>()?;
+	let version = client_version.min(PROT_VERSION);
+	
+	let mut reply = Vec::with_capacity(PROT_MAGIC.len() + 2);
+	reply.extend_from_slice(&PROT_MAGIC);
+	reply.write_u16::<NetworkEndian>(version)?;
+	stream.write_all(&reply)?;
+	stream.flush()?;
+	
+	if version < 2 {
+		return Err(Error::new(ErrorKind::InvalidData, format!("unsupported protocol version {}", client_version)));
+	}
+	
+	Ok(version)
+	
+}
+
+/// Reads a frame from the stream
+pub fn read_frame<R: Read>(stream: &mut R) -> std::io::Result<Frame> {
+	
+	let length = stream.read_u32::<NetworkEndian>()?;
+	if !(4 + 1..=MAX_FRAME_SIZE).contains(&length) {
+		return Err(Error::new(ErrorKind::InvalidData, format!("invalid frame length {}", length)));
+	}
+	
+	let request_id = stream.read_u32::<NetworkEndian>()?;
+	let msg_type = stream.read_u8()?;
+	
+	let mut payload = vec![0u8; length as usize - 4 - 1];
+	stream.read_exact(&mut payload)?;
+	
+	Ok(Frame {
+		request_id,
+		msg_type,
+		payload
+	})
+	
+}
+
+/// Writes a frame to the stream in a single write
+pub fn write_frame<W: Write>(stream: &mut W, request_id: u32, msg_type: u8, payload: &[u8]) -> std::io::Result<()> {
+	
+	let mut buf = Vec::with_capacity(4 + 4 + 1 + payload.len());
+	buf.write_u32::<NetworkEndian>((4 + 1 + payload.len()) as u32)?;
+	buf.write_u32::<NetworkEndian>(request_id)?;
+	buf.write_u8(msg_type)?;
+	buf.extend_from_slice(payload);
+	
+	stream.write_all(&buf)?;
+	stream.flush()
+	
+}
@@ -0,0 +1,398 @@
+use std::time::{Duration, Instant};
+use crate::config::EngineConfig;
+use crate::native_network::{relu, Dense, InputLayer, Kernel, NativeNetwork, KERNEL_BOARDS};
+use crate::neural_heuristic::NNBackend;
+use crate::othello_board::{generate_moves, make_move, to_bit_move_vec};
+use crate::othello_state::OthelloState;
+
+/// Largest quantized activation, the i32 sum of a row can't overflow with up to MAX_INPUTS inputs
+const ACTIVATION_MAX: f32 = 4095.0;
+const MAX_INPUTS: usize = 4096;
+
+/// Fraction of the positive activations of a layer on the calibration positions that aren't clipped
+const CALIBRATION_QUANTILE: f64 = 0.9999;
+/// Number of positions from random games the activations are calibrated on
+pub const CALIBRATION_POSITIONS: usize = 4096;
+pub const CALIBRATION_SEED: u64 = 0x9E3779B97F4A7C15;
+
+/// Positions of other random games the quantization report compares the networks on
+const TEST_POSITIONS: usize = 20000;
+const TEST_SEED: u64 = 0xD1B54A32D192ED03;
+/// Number of test positions the best moves at 1 ply are compared on
+const TEST_MOVE_POSITIONS: usize = 2000;
+/// Boards per predict when timing the networks, about a node's children in the midgame search
+const TEST_BATCH: usize = 16;
+
+/// A Linear layer with int8 weights scaled per output row, run on int16 activations
+struct QuantizedDense {
+	inputs: usize,
+	outputs: usize,
+	weights: Vec<i8>,
+	/// converts the i32 sum of a row back to f32, the weight scale of the row over the input scale
+	row_scales: Vec<f32>,
+	bias: Vec<f32>,
+	/// an input x is quantized to round(x * input_scale)
+	input_scale: f32
+}
+
+impl QuantizedDense {
+	
+	/// Quantizes the weights of `layer`, inputs above `input_max` are clipped
+	fn new(layer: &Dense, input_max: f32) -> Self {
+		
+		let input_scale = if input_max > 0.0 { ACTIVATION_MAX / input_max } else { 1.0 };
+		
+		let mut weights = Vec::with_capacity(layer.weights.len());
+		let mut row_scales = Vec::with_capacity(layer.outputs);
+		for o in 0..layer.outputs {
+			let row = layer.row(o);
+			let max = row.iter().fold(0.0f32, |max, w| max.max(w.abs()));
+			let scale = if max > 0.0 { max / 127.0 } else { 1.0 };
+			weights.extend(row.iter().map(|w| (w / scale).round() as i8));
+			row_scales.push(scale / input_scale);
+		}
+		
+		QuantizedDense {
+			inputs: layer.inputs,
+			outputs: layer.outputs,
+			weights,
+			row_scales,
+			bias: layer.bias.clone(),
+			input_scale
+		}
+		
+	}
+	
+	fn num_params(&self) -> usize {
+		self.weights.len() + self.bias.len()
+	}
+	
+	#[inline(always)]
+	fn row(&self, o: usize) -> &[i8] {
+		&self.weights[o * self.inputs..(o + 1) * self.inputs]
+	}
+	
+	/// Quantizes activations after a ReLU for this layer
+	fn quantize(&self, x: &[f32]) -> Vec<i16> {
+		x.iter().map(|x| (x * self.input_scale).round().min(ACTIVATION_MAX) as i16).collect()
+	}
+	
+	/// Adds the layer applied to each row of `input` to the rows of `out`
+	/// Boards go through in groups of KERNEL_BOARDS, like the float layers
+	fn add(&self, kernel: Kernel, input: &[f32], out: &mut [f32]) {
+		
+		let (inputs, outputs) = (self.inputs, self.outputs);
+		let input = self.quantize(input);
+		let boards = out.len() / outputs;
+		
+		let mut b = 0;
+		while b + KERNEL_BOARDS <= boards {
+			let x: [&[i16]; KERNEL_BOARDS] = std::array::from_fn(|k| &input[(b + k) * inputs..(b + k + 1) * inputs]);
+			for o in 0..outputs {
+				let sums = dot4_i8(kernel, self.row(o), x);
+				for (k, sum) in sums.iter().enumerate() {
+					out[(b + k) * outputs + o] += self.bias[o] + *sum as f32 * self.row_scales[o];
+				}
+			}
+			b += KERNEL_BOARDS;
+		}
+		
+		// fewer boards than a group are left
+		for b in b..boards {
+			let x = &input[b * inputs..(b + 1) * inputs];
+			for o in 0..outputs {
+				out[b * outputs + o] += self.bias[o] + dot_i8(kernel, self.row(o), x) as f32 * self.row_scales[o];
+			}
+		}
+		
+	}
+	
+}
+
+/// The native network with int8 weights in the residual blocks and head
+/// The input layer only adds columns and the output layer is tiny, so they stay f32
+pub struct QuantizedNetwork {
+	input: InputLayer,
+	blocks: Vec<(QuantizedDense, QuantizedDense)>,
+	head: QuantizedDense,
+	output: Dense,
+	kernel: Kernel
+}
+
+impl QuantizedNetwork {
+	
+	/// Quantizes `network`, the scale of each layer's inputs comes from
+	/// the activations of the float network on the calibration `positions`
+	pub fn calibrate(network: &NativeNetwork, positions: &[(u64, u64)]) -> Self {
+		
+		assert!(network.input.outputs() <= MAX_INPUTS, "networks with over {} hidden units can't be quantized", MAX_INPUTS);
+		
+		let mut input_max = vec![];
+		network.forward(positions, |x| input_max.push(activation_quantile(x, CALIBRATION_QUANTILE)));
+		let mut input_max = input_max.into_iter();
+		
+		// forward observes the inputs in the same order
+		let blocks = network.blocks.iter()
+			.map(|(l1, l2)| (QuantizedDense::new(l1, input_max.next().unwrap()), QuantizedDense::new(l2, input_max.next().unwrap())))
+			.collect();
+		let head = QuantizedDense::new(&network.head, input_max.next().unwrap());
+		
+		QuantizedNetwork {
+			input: network.input.clone(),
+			blocks,
+			head,
+			output: network.output.clone(),
+			kernel: Kernel::detect()
+		}
+		
+	}
+	
+	/// Counts the parameters for nice log messages
+	pub fn num_params(&self) -> usize {
+		self.input.num_params()
+			+ self.blocks.iter().map(|(l1, l2)| l1.num_params() + l2.num_params()).sum::<usize>()
+			+ self.head.num_params() + self.output.num_params()
+	}
+	
+	/// Name of the kernels used on this cpu
+	pub fn kernel_name(&self) -> String {
+		format!("{:?}", self.kernel).to_lowercase()
+	}
+	
+}
+
+impl NNBackend for QuantizedNetwork {
+	fn predict(&self, boards: &[(u64, u64)]) -> Vec<f32> {
+		
+		let mut x = self.input.apply(boards);
+		
+		let mut t = vec![0.0; x.len()];
+		for (l1, l2) in self.blocks.iter() {
+			t.fill(0.0);
+			l1.add(self.kernel, &x, &mut t);
+			relu(&mut t);
+			l2.add(self.kernel, &t, &mut x);
+			relu(&mut x);
+		}
+		
+		let mut h = vec![0.0; boards.len() * self.head.outputs];
+		self.head.add(self.kernel, &x, &mut h);
+		relu(&mut h);
+		
+		h.chunks_exact(self.head.outputs)
+			.map(|h| (self.output.bias[0] + self.kernel.dot(self.output.row(0), h)).tanh())
+			.collect()
+		
+	}
+}
+
+/// The activation a `quantile` of the positive activations are below, 0 if there are none
+fn activation_quantile(x: &[f32], quantile: f64) -> f32 {
+	
+	let mut positive: Vec<f32> = x.iter().copied().filter(|x| *x > 0.0).collect();
+	if positive.is_empty() {
+		return 0.0;
+	}
+	
+	let i = ((positive.len() - 1) as f64 * quantile) as usize;
+	*positive.select_nth_unstable_by(i, |a, b| a.total_cmp(b)).1
+	
+}
+
+/// Positions (me, enemy) with a move, from random games with a fixed seed
+pub fn random_game_positions(n: usize, seed: u64) -> Vec<(u64, u64)> {
+	
+	// xorshift64
+	let mut state = seed | 1;
+	let mut next_random = move || {
+		state ^= state << 13;
+		state ^= state >> 7;
+		state ^= state << 17;
+		state
+	};
+	
+	let mut positions = Vec::with_capacity(n);
+	while positions.len() < n {
+		
+		let start = OthelloState::starting_state();
+		let (mut me, mut enemy) = (start.black(), start.white());
+		
+		loop {
+			
+			let mut moves = generate_moves(me, enemy);
+			if moves == 0 {
+				if generate_moves(enemy, me) == 0 {
+					break;
+				}
+				std::mem::swap(&mut me, &mut enemy);
+				continue;
+			}
+			
+			positions.push((me, enemy));
+			
+			for _ in 0..next_random() % moves.count_ones() as u64 {
+				moves &= moves - 1;
+			}
+			let (new_me, new_enemy) = make_move(moves & moves.wrapping_neg(), me, enemy);
+			me = new_enemy;
+			enemy = new_me;
+			
+		}
+		
+	}
+	
+	positions.truncate(n);
+	positions
+	
+}
+
+/// Prints how far the quantized network is from the float network on positions of random games
+/// Returns the error if the weights can't be loaded
+pub fn quantization_report(config: &EngineConfig) -> std::io::Result<()> {
+	
+	let network = NativeNetwork::load(&config.weights_path)?;
+	let quantized = QuantizedNetwork::calibrate(&network, &random_game_positions(CALIBRATION_POSITIONS, CALIBRATION_SEED));
+	println!("Quantized {} params with {} kernels, calibrated on {} positions", quantized.num_params(), quantized.kernel_name(), CALIBRATION_POSITIONS);
+	
+	let positions = random_game_positions(TEST_POSITIONS, TEST_SEED);
+	let (expected, float_time) = timed_predict(&network, &positions);
+	let (actual, quantized_time) = timed_predict(&quantized, &positions);
+	
+	// in disks, the search scores an eval q as 64 * q
+	let mut errors: Vec<f64> = expected.iter()
+		.zip(actual.iter())
+		.map(|(e, q)| 64.0 * (e - q).abs() as f64)
+		.collect();
+	errors.sort_by(|a, b| a.total_cmp(b));
+	let n = errors.len() as f64;
+	println!("Error on {} positions (disks): mean {:.4}, rms {:.4}, 99% {:.4}, max {:.4}",
+		errors.len(),
+		errors.iter().sum::<f64>() / n,
+		(errors.iter().map(|e| e * e).sum::<f64>() / n).sqrt(),
+		errors[(errors.len() - 1) * 99 / 100],
+		errors[errors.len() - 1]
+	);
+	
+	// the best move at 1 ply has the lowest eval for the enemy
+	let best_move = |model: &dyn NNBackend, me: u64, enemy: u64| {
+		let moves = to_bit_move_vec(generate_moves(me, enemy));
+		let children: Vec<(u64, u64)> = moves.iter()
+			.map(|mov| make_move(*mov, me, enemy))
+			.map(|(me, enemy)| (enemy, me))
+			.collect();
+		let evals = model.predict(&children);
+		moves.iter().zip(evals.iter()).min_by(|a, b| a.1.total_cmp(b.1)).map(|(mov, _)| *mov)
+	};
+	let same_moves = positions.iter()
+		.take(TEST_MOVE_POSITIONS)
+		.filter(|(me, enemy)| best_move(&network, *me, *enemy) == best_move(&quantized, *me, *enemy))
+		.count();
+	println!("Same best move at 1 ply on {} of {} positions", same_moves, TEST_MOVE_POSITIONS.min(positions.len()));
+	
+	println!("Time for {} boards in batches of {}: float {:.1}ms, quantized {:.1}ms, speedup {:.2}x",
+		positions.len(),
+		TEST_BATCH,
+		float_time.as_secs_f64() * 1000.0,
+		quantized_time.as_secs_f64() * 1000.0,
+		float_time.as_secs_f64() / quantized_time.as_secs_f64()
+	);
+	
+	Ok(())
+	
+}
+
+/// Evals all the boards in batches of TEST_BATCH, timing them
+fn timed_predict(model: &dyn NNBackend, boards: &[(u64, u64)]) -> (Vec<f32>, Duration) {
+	let start = Instant::now();
+	let evals = boards.chunks(TEST_BATCH).flat_map(|batch| model.predict(batch)).collect();
+	(evals, start.elapsed())
+}
+
+#[inline(always)]
+fn dot4_i8(kernel: Kernel, w: &[i8], x: [&[i16]; KERNEL_BOARDS]) -> [i32; KERNEL_BOARDS] {
+	match kernel {
+		Kernel::Scalar => x.map(|x| dot_i8_scalar(w, x)),
+		// SAFETY: the cpu supports avx2, checked in Kernel::detect
+		#[cfg(target_arch = "x86_64")]
+		Kernel::Avx2 => unsafe { dot4_i8_avx2(w, x) }
+	}
+}
+
+#[inline(always)]
+fn dot_i8(kernel: Kernel, w: &[i8], x: &[i16]) -> i32 {
+	match kernel {
+		Kernel::Scalar => dot_i8_scalar(w, x),
+		#[cfg(target_arch = "x86_64")]
+		Kernel::Avx2 => unsafe { dot_i8_avx2(w, x) }
+	}
+}
+
+#[inline(always)]
+fn dot_i8_scalar(w: &[i8], x: &[i16]) -> i32 {
+	w.iter().zip(x.iter()).map(|(w, x)| *w as i32 * *x as i32).sum()
+}
+
+/// Dot products of `w` with 4 inputs at once, 16 weights are widened to i16 and multiply-added into i32s
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn dot4_i8_avx2(w: &[i8], x: [&[i16]; KERNEL_BOARDS]) -> [i32; KERNEL_BOARDS] {
+	
+	use std::arch::x86_64::*;
+	
+	let n = w.len();
+	let mut acc = [_mm256_setzero_si256(); KERNEL_BOARDS];
+	
+	let mut i = 0;
+	while i + 16 <= n {
+		let wv = _mm256_cvtepi8_epi16(_mm_loadu_si128(w.as_ptr().add(i) as *const __m128i));
+		for (acc, x) in acc.iter_mut().zip(x.iter()) {
+			let xv = _mm256_loadu_si256(x.as_ptr().add(i) as *const __m256i);
+			*acc = _mm256_add_epi32(*acc, _mm256_madd_epi16(wv, xv));
+		}
+		i += 16;
+	}
+	
+	let mut sums = [0; KERNEL_BOARDS];
+	for ((sum, acc), x) in sums.iter_mut().zip(acc.iter()).zip(x.iter()) {
+		*sum = hsum_i32_avx2(*acc) + dot_i8_scalar(&w[i..], &x[i..]);
+	}
+	
+	sums
+	
+}
+
+/// Dot product of `w` and `x`, 16 weights are widened to i16 and multiply-added into i32s
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn dot_i8_avx2(w: &[i8], x: &[i16]) -> i32 {
+	
+	use std::arch::x86_64::*;
+	
+	let n = w.len();
+	let mut acc = _mm256_setzero_si256();
+	
+	let mut i = 0;
+	while i + 16 <= n {
+		let wv = _mm256_cvtepi8_epi16(_mm_loadu_si128(w.as_ptr().add(i) as *const __m128i));
+		let xv = _mm256_loadu_si256(x.as_ptr().add(i) as *const __m256i);
+		acc = _mm256_add_epi32(acc, _mm256_madd_epi16(wv, xv));
+		i += 16;
+	}
+	
+	hsum_i32_avx2(acc) + dot_i8_scalar(&w[i..], &x[i..])
+	
+}
+
+/// Sum of the 8 lanes
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn hsum_i32_avx2(v: std::arch::x86_64::__m256i) -> i32 {
+	
+	use std::arch::x86_64::*;
+	
+	let v = _mm_add_epi32(_mm256_castsi256_si128(v), _mm256_extracti128_si256(v, 1));
+	let v = _mm_add_epi32(v, _mm_shuffle_epi32(v, 0b01_00_11_10));
+	let v = _mm_add_epi32(v, _mm_shuffle_epi32(v, 0b10_11_00_01));
+	_mm_cvtsi128_si32(v)
+	
+}
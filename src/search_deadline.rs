@@ -0,0 +1,50 @@
+use std::cell::Cell;
+use std::time::Instant;
+
+/// Stops a search once a wall-clock deadline has passed
+/// Once expired, a search returns right away and its result must be thrown away
+pub struct SearchDeadline {
+	deadline: Option<Instant>,
+	/// set the first time the deadline is noticed, so later checks skip the clock
+	expired: Cell<bool>
+}
+
+impl SearchDeadline {
+	
+	/// A deadline that never expires
+	pub fn none() -> Self {
+		SearchDeadline {
+			deadline: None,
+			expired: Cell::new(false)
+		}
+	}
+	
+	/// A deadline that expires at `deadline`
+	pub fn at(deadline: Instant) -> Self {
+		SearchDeadline {
+			deadline: Some(deadline),
+			expired: Cell::new(false)
+		}
+	}
+	
+	/// Checks the clock, returns true if the search should stop
+	#[inline(always)]
+	pub fn check(&self) -> bool {
+		if self.expired.get() {
+			return true;
+		}
+		match self.deadline {
+			Some(deadline) if Instant::now() >= deadline => {
+				self.expired.set(true);
+				true
+			}
+			_ => false
+		}
+	}
+	
+	/// True if the deadline was noticed by a search, without checking the clock
+	pub fn expired(&self) -> bool {
+		self.expired.get()
+	}
+	
+}
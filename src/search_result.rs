@@ -0,0 +1,80 @@
+use crate::endgame::Confidence;
+
+/// Max number of moves in a PvLine. Longer lines are cut off
+const MAX_PV_LEN: usize = 64;
+
+/// What the score of a search result is known to be
+/// The values are sent to v3 clients
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ScoreKind {
+	/// an estimate from the midgame search or the book
+	Heuristic = 0,
+	/// the exact final disk difference
+	Exact = 1,
+	/// only the winner is known, the score is the sign of the disk difference
+	Wld = 2,
+	/// the winner is known and the margin is at least |score|
+	Bound = 3,
+	/// the score of a selective endgame solve, which is likely but not proven to be exact
+	Selective = 4
+}
+
+/// Result of a search
+pub struct SearchResult {
+	/// the best move, 65 if there is no move (pass or game over)
+	pub best_move: u8,
+	/// eval from the current player's POV
+	/// centidisks for the midgame search, disks for the endgame solver
+	pub score: i32,
+	/// the expected line, starting with best_move. passes are 65
+	pub pv: Vec<u8>,
+	pub kind: ScoreKind,
+	/// the confidence level of a Selective score, None for any other kind
+	pub confidence: Option<Confidence>
+}
+
+/// A principal variation that is built up while searching
+/// Moves are stored inline to keep allocations out of the search
+#[derive(Clone, Copy)]
+pub struct PvLine {
+	len: u8,
+	moves: [u8; MAX_PV_LEN]
+}
+
+impl PvLine {
+	
+	pub fn new() -> Self {
+		PvLine {
+			len: 0,
+			moves: [0; MAX_PV_LEN]
+		}
+	}
+	
+	/// Empties the line
+	#[inline(always)]
+	pub fn clear(&mut self) {
+		self.len = 0;
+	}
+	
+	/// Sets this line to just `mov`, or an empty line if `mov` is 64 (no move)
+	#[inline(always)]
+	pub fn set_move(&mut self, mov: u8) {
+		self.moves[0] = mov;
+		self.len = (mov != 64) as u8;
+	}
+	
+	/// Sets this line to `mov` followed by the line of the child node
+	#[inline(always)]
+	pub fn set(&mut self, mov: u8, child: &PvLine) {
+		let len = (child.len as usize).min(MAX_PV_LEN - 1);
+		self.moves[0] = mov;
+		self.moves[1..len + 1].copy_from_slice(&child.moves[..len]);
+		self.len = (len + 1) as u8;
+	}
+	
+	pub fn moves(&self) -> &[u8] {
+		&self.moves[..self.len as usize]
+	}
+	
+}
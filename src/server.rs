@@ -4,51 +4,192 @@ use std::cmp::max;
 use std::fmt::{Display, Formatter};
 use std::thread;
 use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
-use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
 use byteorder::{NetworkEndian, WriteBytesExt};
+#[cfg(feature = "torch")]
 use tch::{CModule, Device, Kind};
 use threadpool::ThreadPool;
-use crate::neural_search::nnsearch_root;
-use crate::othello_board::{empty_disks, evaluation, game_over, generate_moves};
-use crate::endgame::solve_endgame_root;
+use crate::neural_search::{nnsearch_root, nnsearch_root_iterative, nnsearch_root_multipv};
+use crate::othello_board::{BoardKernel, empty_disks, evaluation, game_over, generate_moves};
+use crate::endgame::{solve_endgame_root_multipv, solve_endgame_selective, solve_endgame_wld_first, Confidence, EgLimits};
 use crate::opening_book::{OthelloBook, read_book, search_book};
+use crate::time_manager::{TimeManager, TimePlan};
+use crate::search_result::{ScoreKind, SearchResult};
+use crate::config::{Backend, DepthLimits, EngineConfig, EvaluatorKind};
+use crate::evaluator::{ClassicEvaluator, DiskMobilityEvaluator, Evaluator, MidgameEvaluator, NNEvaluator, PatternEvaluator, RandomEvaluator};
+use crate::model_pool::{ModelPool, NNProvider};
+use crate::transposition_table::{TableClient, TranspositionTable};
+use crate::eval_cache::{CachedBackend, EvalCache};
+use crate::inference_service::InferenceService;
+use crate::native_network::NativeNetwork;
+use crate::quantized_network::{random_game_positions, QuantizedNetwork, CALIBRATION_POSITIONS, CALIBRATION_SEED};
+use crate::neural_heuristic::{NNBackend, SymmetryAveraged};
+use crate::protocol::{accept_handshake, read_frame, write_frame, ERR_MALFORMED, ERR_UNKNOWN_MESSAGE, LEGACY_ERROR_MOVE, LEGACY_REQUEST_SIZE, MSG_ANALYZE, MSG_ANALYZE_REPLY, MSG_CLOSE, MSG_ERROR, MSG_PING, MSG_PONG, MSG_SEARCH, MSG_SEARCH_REPLY, MSG_SEARCH_TIMED, TIMED_REQUEST_SIZE, PROT_MAGIC, READ_TIMEOUT, ServerError, STATUS_NO_BOOK, STATUS_NO_MODEL, WRITE_TIMEOUT};
 
-struct SearchParams {
+/// Everything needed to search, shared by all clients
+pub struct Engine {
+	pub book: OthelloBook,
+	pub evaluator: MidgameEvaluator,
+	/// speed estimates used by the T bit
+	pub time_manager: TimeManager,
+	/// midgame search results, kept between requests so later moves of a game reuse them
+	pub mid_tt: TranspositionTable,
+	/// endgame solver bounds, kept between requests like the midgame table
+	pub eg_tt: TranspositionTable,
+	/// network evals, kept between requests like the tables
+	pub eval_cache: EvalCache,
+	/// number of symmetries evals are averaged over when a search sets the Y bit
+	pub sym_evals: usize,
+	/// threads used by each endgame solve
+	pub eg_threads: usize,
+	/// endgame solves that search more nodes or take longer are aborted, None for no limit
+	pub eg_max_nodes: Option<u64>,
+	pub eg_max_time: Option<Duration>,
+	/// endgames with up to this many empties that are too deep to solve exactly are solved selectively
+	pub eg_selective_empties: u8,
+	pub eg_confidence: Confidence,
+	/// max depths a client can request
+	pub limits: DepthLimits,
+	/// what the engine runs without
+	pub status: EngineStatus
+}
+
+/// What the engine is running without, reported in the logs and in replies to v4 clients
+/// A book or evaluator left out of the config on purpose isn't missing
+#[derive(Clone, Copy, Default)]
+pub struct EngineStatus {
+	/// the neural network failed to load, midgame searches use the classic evaluator
+	pub no_model: bool,
+	/// the opening book failed to load, searches never use a book move
+	pub no_book: bool
+}
+
+impl EngineStatus {
+	
+	/// True if the engine is running without something it was configured with
+	pub fn degraded(&self) -> bool {
+		self.no_model || self.no_book
+	}
+	
+	/// Status byte of v4 replies, see STATUS_NO_MODEL and STATUS_NO_BOOK
+	pub fn flags(&self) -> u8 {
+		let mut flags = 0;
+		if self.no_model {
+			flags |= STATUS_NO_MODEL;
+		}
+		if self.no_book {
+			flags |= STATUS_NO_BOOK;
+		}
+		flags
+	}
+	
+}
+
+impl Display for EngineStatus {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match (self.no_model, self.no_book) {
+			(false, false) => write!(f, "ok"),
+			(true, false) => write!(f, "degraded, no model"),
+			(false, true) => write!(f, "degraded, no book"),
+			(true, true) => write!(f, "degraded, no model and no book")
+		}
+	}
+}
+
+impl Engine {
+	
+	/// Number of symmetries the evals of a search are averaged over
+	fn eval_symmetries(&self, params: &SearchParams) -> usize {
+		if params.sym_average { self.sym_evals } else { 1 }
+	}
+	
+	/// Runs a midgame search with the engine's evaluator
+	/// Network evals go through the eval cache, averaged over the symmetries of the board if the search asks for it,
+	/// and the hit rate of the search and of all searches so far is logged
+	pub fn with_evaluator<T, F: FnOnce(&dyn Evaluator) -> T>(&self, params: &SearchParams, f: F) -> T {
+		match &self.evaluator {
+			MidgameEvaluator::NN(nn) => {
+				let symmetries = self.eval_symmetries(params);
+				nn.with_backend(|model| {
+					let averaged = SymmetryAveraged { model, symmetries };
+					let model: &dyn NNBackend = if symmetries > 1 { &averaged } else { model };
+					let cached = CachedBackend::new(model, &self.eval_cache, symmetries);
+					let result = f(&NNEvaluator::new(&cached, symmetries));
+					let (hits, lookups) = cached.stats();
+					drop(cached);
+					eprintln!("Eval cache hits: {} of {} ({:.1}%), {:.1}% of all searches", hits, lookups, 100.0 * hits as f64 / lookups.max(1) as f64, 100.0 * self.eval_cache.hit_rate());
+					result
+				})
+			}
+			MidgameEvaluator::Pattern => f(&PatternEvaluator),
+			MidgameEvaluator::DiskMobility => f(&DiskMobilityEvaluator),
+			MidgameEvaluator::Classic => f(&ClassicEvaluator),
+			MidgameEvaluator::Random(random) => f(random)
+		}
+	}
+	
+	/// Limits of an endgame solve started at `start`
+	/// A timed search only gives part of its budget to the solve, the rest is left for the midgame fallback
+	fn eg_limits(&self, start: Instant, move_time: Option<Duration>) -> EgLimits {
+		let solve_time = move_time.map(|move_time| move_time.mul_f64(SOFT_TIME_FRACTION));
+		let max_time = match (self.eg_max_time, solve_time) {
+			(Some(max_time), Some(solve_time)) => Some(max_time.min(solve_time)),
+			(max_time, solve_time) => max_time.or(solve_time)
+		};
+		EgLimits {
+			max_nodes: self.eg_max_nodes,
+			deadline: max_time.map(|max_time| start + max_time)
+		}
+	}
+	
+}
+
+pub struct SearchParams {
 	/// If true will use remaining time to adjust settings
-	adj_time: bool,
+	pub adj_time: bool,
 	/// If true will use the opening book
-	use_book: bool,
+	pub use_book: bool,
 	/// If true will solve exact endgame, If false solves WLD
-	solve_end_exact: bool,
+	pub solve_end_exact: bool,
 	/// If true will do a WLD endgame search on deep searches (> 15 ply)
-	solve_end_adaptive: bool,
+	pub solve_end_adaptive: bool,
 	/// Mid game (nn_search) search depth
-	mid_depth: u8,
+	pub mid_depth: u8,
 	/// Endgame search depth
-	end_depth: u8
+	pub end_depth: u8,
+	/// If true will reply with the principal variation
+	pub extended_reply: bool,
+	/// Wall-clock budget for the midgame search, which then deepens up to mid_depth until it runs out
+	/// None searches to exactly mid_depth
+	pub move_time: Option<Duration>,
+	/// If true will average the evals over the symmetries of the board (slower, but more accurate)
+	pub sym_average: bool
 }
 
 impl Display for SearchParams {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-		write!(f, "SearchParams(tada={}, book={}, exact={}, egada={}, md={}, ed={})",
-		       self.adj_time, self.use_book, self.solve_end_exact, self.solve_end_adaptive, self.mid_depth, self.end_depth)
+		write!(f, "SearchParams(tada={}, book={}, exact={}, egada={}, md={}, ed={}, pv={}, mt={:?}, sym={})",
+		       self.adj_time, self.use_book, self.solve_end_exact, self.solve_end_adaptive, self.mid_depth, self.end_depth, self.extended_reply, self.move_time, self.sym_average)
 	}
 }
 
 impl SearchParams {
 	
 	/// Decodes algorithm parameters requested from client
-	/// params: __TBSADDDDDEEEEE
+	/// params: YPTBSADDDDDEEEEE
 	///         5432109876543210
+	/// Y - bit to average the evals over the symmetries of the board (1 = average, 0 = single eval)
+	/// P - bit to reply with the principal variation (1 = extended reply, 0 = move and eval only)
 	/// T - bit to adjust based on time (1 = adjust params to fit in remaining time, 0 = ignore remaining time)
 	/// B - bit to use the opening book (1 = use book, 0 = no book)
 	/// S - bit to solve exact endgame (1 = exact, 0 = WLD)
 	/// A - bit to force WLD on deep endgame searches (WLD on eg depth > 15) (1 = WLD, 0 = exact)
 	/// D - 5 bits for neural network depth (0-31)
 	/// E - 5 bits for endgame depth (0-31)
-	fn from_u16(p: u16) -> Self {
+	/// Depths are clamped to the limits
+	fn from_u16(p: u16, limits: &DepthLimits) -> Self {
 		
 		let mut end_depth = ((p >> 0) & 0b11111) as u8;
 		let mut mid_depth = ((p >> 5) & 0b11111) as u8;
@@ -56,10 +197,12 @@ impl SearchParams {
 		let solve_end_exact = ((p >> 11) & 0b1) != 0;
 		let use_book = ((p >> 12) & 0b1) != 0;
 		let adj_time = ((p >> 13) & 0b1) != 0;
+		let extended_reply = ((p >> 14) & 0b1) != 0;
+		let sym_average = ((p >> 15) & 0b1) != 0;
 		
 		// ensure not too deep
-		end_depth = end_depth.clamp(1, 22);
-		mid_depth = mid_depth.clamp(1, 10);
+		end_depth = end_depth.clamp(1, limits.max_end_depth);
+		mid_depth = mid_depth.clamp(1, limits.max_mid_depth);
 		
 		SearchParams {
 			adj_time,
@@ -67,190 +210,651 @@ impl SearchParams {
 			solve_end_exact,
 			solve_end_adaptive,
 			mid_depth,
-			end_depth
+			end_depth,
+			extended_reply,
+			move_time: None,
+			sym_average
 		}
 		
 	}
 	
+	/// Adjusts the depths and endgame mode to fit in the remaining game time
+	/// The requested depths are used as upper limits
+	fn fit_to_time(&mut self, time_manager: &TimeManager, time: u16, empties: u8) {
+		
+		let requested = TimePlan {
+			mid_depth: self.mid_depth,
+			end_depth: self.end_depth,
+			solve_end_exact: self.solve_end_exact
+		};
+		
+		let plan = time_manager.plan(&requested, time, empties);
+		
+		self.mid_depth = plan.mid_depth;
+		self.end_depth = plan.end_depth;
+		self.solve_end_exact = plan.solve_end_exact;
+		
+	}
+	
 	/// Gets the adjusted depth to pass to midgame search
-	/// for a true depth of mid_depth
-	fn adjusted_mid_depth(&self) -> u8 {
-		if cfg!(feature = "large_batch") {
-			max(1 + 3, self.mid_depth) - 3
-		} else {
-			max(1 + 1, self.mid_depth) - 1
-		}
+	/// for a true depth of mid_depth, with an evaluator that looks `leaf_plies` ahead (see Evaluator::leaf_plies)
+	fn adjusted_mid_depth(&self, leaf_plies: u8) -> u8 {
+		max(1 + leaf_plies, self.mid_depth) - leaf_plies
 	}
 	
-	/// Gets the window size for the endgame alphabeta search
-	/// If solve_end_exact is true, sets to 100, to find exact in -64 to 64
-	/// If solve_end_exact is false, sets to 1 for WLD
-	fn end_window(&self, empties: u8) -> i8 {
+	/// Whether the endgame solve should find the exact score after the WLD result
+	/// If solve_end_exact is false, only WLD is solved
+	fn end_exact(&self, empties: u8) -> bool {
 		
 		// respect WLD bit
 		if !self.solve_end_exact {
-			return 1;
+			return false;
 		}
 		
-		// if adaptive eg, and it's going to be a deep search, do a WDL
-		if self.solve_end_adaptive && empties > 17 {
-			32
-		} else {
-			64
-		}
+		// if adaptive eg, and it's going to be a deep search, only do a WLD
+		!(self.solve_end_adaptive && empties > 17)
 		
 	}
 	
 }
 
-/// Returns (best_move, centidisk_score) for the given position
+/// Returns the best move, centidisk score and principal variation for the given position
 /// Performs search according to search params
-fn server_get_move(book: &OthelloBook, ts_model: &Arc<Mutex<CModule>>, me: u64, enemy: u64, params: &SearchParams) -> (u8, i16) {
+/// An endgame solve that hits its limits falls back to the midgame search, within the same move time
+/// The tables are used as `client`, whose searches only age its own entries
+pub fn server_get_move(engine: &Engine, client: TableClient, me: u64, enemy: u64, params: &SearchParams) -> SearchResult {
+	
+	let start = Instant::now();
 	
 	// if the game is over, return the evaluation
 	if game_over(me, enemy) {
-		return (65, 100 * (evaluation(me, enemy) as i16));
+		return SearchResult { best_move: 65, score: 100 * (evaluation(me, enemy) as i32), pv: vec![], kind: ScoreKind::Exact, confidence: None };
 	}
 	
 	// if there are no moves, pass
 	if generate_moves(me, enemy) == 0 {
-		return (65, i16::MAX);
+		return SearchResult { best_move: 65, score: i16::MAX as i32, pv: vec![65], kind: ScoreKind::Heuristic, confidence: None };
 	}
 	
 	// if there are <= eg depth disks left, solve the endgame
 	let empties = empty_disks(me, enemy);
 	if empties <= params.end_depth {
-		let window = params.end_window(empties);
-		let (mov, q) = solve_endgame_root(me, enemy, -window, window);
-		return (mov, 100 * (q as i16));
+		let exact = params.end_exact(empties);
+		let limits = engine.eg_limits(start, params.move_time);
+		match solve_endgame_wld_first(engine.eg_tt.for_client(client), me, enemy, exact, engine.eg_threads, limits) {
+			Some(result) => {
+				// a solve that ran out of time for the margin doesn't measure the speed
+				if result.kind == ScoreKind::Exact || !exact {
+					engine.time_manager.record_end(empties, exact, start.elapsed().as_secs_f64());
+				}
+				return SearchResult { score: 100 * result.score, ..result };
+			}
+			None => eprintln!("Endgame solve of {} empties hit its limits after {:.2}s, using the midgame search", empties, start.elapsed().as_secs_f64())
+		}
+	} else if empties <= engine.eg_selective_empties {
+		// too deep for an exact solve, but a selective solve is still better than the midgame search
+		let limits = engine.eg_limits(start, params.move_time);
+		match solve_endgame_selective(engine.eg_tt.for_client(client), me, enemy, engine.eg_confidence, engine.eg_threads, limits) {
+			Some(result) => {
+				let percent = result.confidence.map_or(0, Confidence::percent);
+				eprintln!("Selective solve of {} empties at {}% confidence in {:.2}s", empties, percent, start.elapsed().as_secs_f64());
+				return SearchResult { score: 100 * result.score, ..result };
+			}
+			None => eprintln!("Selective solve of {} empties hit its limits after {:.2}s, using the midgame search", empties, start.elapsed().as_secs_f64())
+		}
 	}
 	
 	// try the opening book
 	if params.use_book {
-		if let Some(res) = search_book(book, me, enemy) {
-			return res;
+		if let Some((mov, q)) = search_book(&engine.book, me, enemy) {
+			return SearchResult { best_move: mov, score: q as i32, pv: vec![mov], kind: ScoreKind::Heuristic, confidence: None };
 		}
 	}
 	
 	// otherwise perform a negamax neural network search
-	let model = ts_model.lock().unwrap();
-	let (mov, q) = nnsearch_root(&*model, me, enemy, -640000, 640000, params.adjusted_mid_depth() as i8);
-	(mov, q as i16)
+	let before = Instant::now();
+	let result = engine.with_evaluator(params, |eval| {
+		match params.move_time {
+			// deepen until the time runs out, the time left if the endgame solve was aborted
+			Some(move_time) => {
+				let hard_deadline = start + move_time;
+				let soft_deadline = before + hard_deadline.saturating_duration_since(before).mul_f64(SOFT_TIME_FRACTION);
+				nnsearch_root_iterative(eval, engine.mid_tt.for_client(client), me, enemy, params.adjusted_mid_depth(eval.leaf_plies()) as i8, soft_deadline, hard_deadline).0
+			}
+			None => nnsearch_root(eval, engine.mid_tt.for_client(client), me, enemy, -640000, 640000, params.adjusted_mid_depth(eval.leaf_plies()) as i8)
+		}
+	});
+	// only a fixed depth search with single evals measures the speed for that depth
+	if params.move_time.is_none() && !params.sym_average {
+		engine.time_manager.record_mid(params.mid_depth, before.elapsed().as_secs_f64());
+	}
+	result
 	
 }
 
-/// Handle client
-/// Protocol expects 20 bytes
+/// Scores every legal move, or the best `n` moves if n > 0, best first
+/// Endgames are solved exactly and scored in centidisks, like server_get_move.
+/// The book isn't used since it only knows the best move, and time isn't recorded
+/// since an analysis is slower than a normal search of the same depth
+pub fn server_get_analysis(engine: &Engine, client: TableClient, me: u64, enemy: u64, params: &SearchParams, n: usize) -> Vec<SearchResult> {
+	
+	// if the game is over, there is nothing to analyze
+	if game_over(me, enemy) {
+		return vec![];
+	}
+	
+	// if there are no moves, pass
+	if generate_moves(me, enemy) == 0 {
+		return vec![SearchResult { best_move: 65, score: i16::MAX as i32, pv: vec![65], kind: ScoreKind::Heuristic, confidence: None }];
+	}
+	
+	// if there are <= eg depth disks left, solve every move
+	let empties = empty_disks(me, enemy);
+	if empties <= params.end_depth {
+		let start = Instant::now();
+		match solve_endgame_root_multipv(engine.eg_tt.for_client(client), me, enemy, n, engine.eg_threads, engine.eg_limits(start, None)) {
+			Some(results) => {
+				return results.into_iter()
+					.map(|result| SearchResult { score: 100 * result.score, ..result })
+					.collect();
+			}
+			None => eprintln!("Endgame analysis of {} empties hit its limits after {:.2}s, using the midgame search", empties, start.elapsed().as_secs_f64())
+		}
+	}
+	
+	engine.with_evaluator(params, |eval| {
+		nnsearch_root_multipv(eval, engine.mid_tt.for_client(client), me, enemy, params.adjusted_mid_depth(eval.leaf_plies()) as i8, n)
+	})
+	
+}
+
+/// Encodes the reply to a search request
+/// !Bh of best move and centidisk eval
+/// If with_kind (protocol v3), followed by !B of the score kind (see ScoreKind),
+/// and for a Selective score by !B of its confidence in %
+/// If there is a status (protocol v4), followed by !B of the engine status (see EngineStatus::flags)
+/// If extended, followed by !B of the pv length and the pv moves (!B each)
+fn encode_reply(result: &SearchResult, extended: bool, with_kind: bool, status: Option<u8>) -> Vec<u8> {
+	
+	let mut reply = vec![result.best_move];
+	reply.extend_from_slice(&(result.score as i16).to_be_bytes());
+	
+	if with_kind {
+		reply.push(result.kind as u8);
+		// selective scores are followed by their confidence in %
+		if let Some(confidence) = result.confidence {
+			reply.push(confidence.percent());
+		}
+	}
+	
+	if let Some(status) = status {
+		reply.push(status);
+	}
+	
+	if extended {
+		reply.push(result.pv.len() as u8);
+		reply.extend_from_slice(&result.pv);
+	}
+	
+	reply
+	
+}
+
+/// Fraction of a move time after which no new iteration is started
+/// The next iteration would take a few times longer than the last, so it likely wouldn't finish
+const SOFT_TIME_FRACTION: f64 = 0.5;
+
+/// Center squares, which are occupied in every game position
+const CENTER_MASK: u64 = 0x0000001818000000;
+
+/// Encodes the legacy reply to a request that couldn't be handled
+/// !Bh of LEGACY_ERROR_MOVE and the error code
+fn encode_legacy_error(code: u8) -> Vec<u8> {
+	let mut reply = vec![LEGACY_ERROR_MOVE];
+	reply.extend_from_slice(&(code as i16).to_be_bytes());
+	reply
+}
+
+/// Parses a request, evaluates the position and returns the encoded reply
+/// Request is 20 bytes
 /// me: u64, enemy: u64, time: u16, params: u16
 /// me: u64 - the bitboard for current player
 /// enemy: u64 - the bitboard for enemy player
 /// time: u16 - the remaining time for the game in 10ths of second
 /// params: u16 - algorithm params
-/// params: __TBSADDDDDEEEEE
+/// params: YPTBSADDDDDEEEEE
+/// Y - bit to average the evals over the symmetries of the board (1 = average, 0 = single eval)
+/// P - bit to reply with the principal variation (1 = extended reply, 0 = move and eval only)
 /// T - bit to adjust based on time (1 = adjust params to fit in remaining time, 0 = ignore remaining time)
 /// B - bit to use the opening book (1 = use book, 0 = no book)
 /// S - bit to solve exact endgame (1 = exact, 0 = WLD)
 /// A - bit to force WLD on deep endgame searches (WLD on eg depth > 15) (1 = WLD, 0 = exact)
 /// D - 5 bits for neural network depth (0-31)
 /// E - 5 bits for endgame depth (0-31)
-fn server_handle_client(book: &OthelloBook, ts_model: &Arc<Mutex<CModule>>, mut stream: TcpStream) {
+/// move_time is an optional budget for the midgame search, see SearchParams
+/// with_kind and status add the score kind and the engine status to the reply, see encode_reply
+fn server_handle_request(engine: &Engine, client: TableClient, data: &[u8; LEGACY_REQUEST_SIZE], move_time: Option<Duration>, with_kind: bool, status: Option<u8>) -> Result<Vec<u8>, ServerError> {
 	
-	const PROT_SIZE: usize = 8 + 8 + 2 + 2;
+	let (me, enemy, time, mut search_params) = parse_request(data, &engine.limits)?;
+	search_params.move_time = move_time;
 	
-	// data from client goes here
-	let mut data = [0 as u8; PROT_SIZE];
-	
-	// read exactly 20 bytes from client
-	let mut buf = [0 as u8; PROT_SIZE];
-	let mut read_bytes = 0;
-	while read_bytes < PROT_SIZE {
-		
-		// read bytes into buffer
-		let read = stream.read(&mut buf)
-			.unwrap_or_else(|_| { println!("Failed to read from client"); 0 });
-		
-		// copy bytes from buffer into data
-		for i in 0..read {
-			data[i + read_bytes] = buf[i];
-		}
-		
-		read_bytes += read;
-		
+	// fit the search into the remaining time
+	if search_params.adj_time {
+		search_params.fit_to_time(&engine.time_manager, time, empty_disks(me, enemy));
 	}
 	
-	// parse client data
+	// evaluate position
+	let before = Instant::now();
+	let result = server_get_move(engine, client, me, enemy, &search_params);
+	let after = Instant::now();
+	
+	let ms = (after - before).as_millis();
+	
+	println!("me={}, e={}, rt={}, mov={}, q={} ({:?}), pv={:?}, t={}ms, p={}, s={}", me, enemy, time, result.best_move, result.score, result.kind, result.pv, ms, search_params, engine.status);
+	
+	Ok(encode_reply(&result, search_params.extended_reply, with_kind, status))
+	
+}
+
+/// Parses a 20 byte request into (me, enemy, time, params), see server_handle_request
+/// Fails if the position can't occur in a game
+fn parse_request(data: &[u8; LEGACY_REQUEST_SIZE], limits: &DepthLimits) -> Result<(u64, u64, u16, SearchParams), ServerError> {
+	
 	let me = u64::from_be_bytes(data[0..8].try_into().unwrap());
 	let enemy = u64::from_be_bytes(data[8..16].try_into().unwrap());
 	let time = u16::from_be_bytes(data[16..18].try_into().unwrap());
 	let params = u16::from_be_bytes(data[18..20].try_into().unwrap());
 	
-	let search_params = SearchParams::from_u16(params);
+	if me & enemy != 0 {
+		return Err(ServerError::InvalidPosition(format!("me and enemy overlap at {:#018x}", me & enemy)));
+	}
+	
+	// disks are never removed, so the starting disks are always there
+	if (me | enemy) & CENTER_MASK != CENTER_MASK {
+		return Err(ServerError::InvalidPosition(format!("empty center squares at {:#018x}", !(me | enemy) & CENTER_MASK)));
+	}
 	
-	// TODO: if search_params.adj_time, change based on time
+	Ok((me, enemy, time, SearchParams::from_u16(params, limits)))
+	
+}
+
+/// Parses an analysis request, scores the moves and returns the encoded reply
+/// Request is a 20 byte search request followed by n: u8, the number of moves to score (0 = all)
+/// The T and P bits are ignored, the search always uses the given depths and replies with pvs
+/// Reply is the number of moves: u8, the engine status: u8 if there is one (protocol v4),
+/// then an extended reply without a status (see encode_reply) per move, best first
+fn server_handle_analysis(engine: &Engine, client: TableClient, data: &[u8; LEGACY_REQUEST_SIZE], n: u8, with_kind: bool, status: Option<u8>) -> Result<Vec<u8>, ServerError> {
+	
+	let (me, enemy, _, search_params) = parse_request(data, &engine.limits)?;
 	
-	// evaluate position
 	let before = Instant::now();
-	let (mov, q) = server_get_move(book, ts_model, me, enemy, &search_params);
-	let after = Instant::now();
+	let results = server_get_analysis(engine, client, me, enemy, &search_params, n as usize);
+	let ms = before.elapsed().as_millis();
 	
-	let ms = (after - before).as_millis();
+	let moves: Vec<(u8, i32)> = results.iter().map(|result| (result.best_move, result.score)).collect();
+	println!("me={}, e={}, n={}, moves={:?}, t={}ms, p={}, s={}", me, enemy, n, moves, ms, search_params, engine.status);
+	
+	let mut reply = vec![results.len() as u8];
+	reply.extend(status);
+	for result in results.iter() {
+		reply.extend(encode_reply(result, true, with_kind, None));
+	}
+	Ok(reply)
+	
+}
+
+/// Runs a request on a search worker and waits for its reply
+/// Connections are read on their own threads, so a worker is only taken while it searches,
+/// and clients that keep idle connections open never hold up the others
+fn run_on_worker<F>(engine: &Arc<Engine>, pool: &ThreadPool, request: F) -> Result<Vec<u8>, ServerError>
+where F: FnOnce(&Engine) -> Result<Vec<u8>, ServerError> + Send + 'static {
+	
+	let (sender, receiver) = mpsc::channel();
+	let engine = engine.clone();
+	pool.execute(move || {
+		// the client may have hung up, then nobody is waiting for the reply
+		let _ = sender.send(request(&engine));
+	});
+	
+	// the sender is dropped without a reply if the search panicked
+	receiver.recv().map_err(|_| ServerError::Io(Error::other("search worker panicked")))?
+	
+}
+
+/// Handle client
+/// A legacy client sends a single 20 byte request (see server_handle_request)
+/// and gets a single reply (see encode_reply) before the connection is closed.
+/// A v2 client starts with PROT_MAGIC and can send many framed requests (see protocol.rs)
+/// Clients that stall in the middle of a message for longer than the timeouts are disconnected,
+/// v2 clients can wait up to idle_timeout between requests (None for no limit)
+/// Runs on the connection's own thread, the searches run on the worker pool (see run_on_worker)
+fn server_handle_client(engine: &Arc<Engine>, pool: &ThreadPool, mut stream: TcpStream, idle_timeout: Option<Duration>) -> Result<(), ServerError> {
+	
+	// a client that stalls mid message would otherwise hold its thread forever
+	stream.set_read_timeout(Some(READ_TIMEOUT))?;
+	stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
 	
-	println!("me={}, e={}, rt={}, mov={}, q={}, t={}ms, p={}", me, enemy, time, mov, q, ms, search_params);
+	// data from client goes here
+	let mut data = [0 as u8; LEGACY_REQUEST_SIZE];
 	
-	// return best move to client
-	stream.write_u8(mov).unwrap_or_else(|_| { println!("Failed to write mov to socket"); });
-	stream.write_i16::<NetworkEndian>(q).unwrap_or_else(|_| { println!("Failed to write q to socket"); });
-	stream.flush().unwrap_or_else(|_| { println!("Failed to flush socket"); });
+	// the first 8 bytes are either the v2 magic or the me bitboard of a legacy request
+	stream.read_exact(&mut data[0..8])?;
+	
+	let result = if data[0..8] == PROT_MAGIC {
+		server_handle_v2(engine, pool, &mut stream, idle_timeout)
+	} else {
+		server_handle_legacy(engine, pool, &mut stream, &mut data)
+	};
+	
+	// the client may have already closed its end
 	stream.shutdown(Shutdown::Both).unwrap_or_else(|_| { println!("Failed to close socket"); });
 	
+	result
+	
 }
 
-#[allow(unused_mut)]
-pub fn server_start(port: u16) {
+/// Handle a legacy client, whose first 8 bytes have already been read into data
+/// A request that can't be handled gets an error reply (see encode_legacy_error)
+fn server_handle_legacy(engine: &Arc<Engine>, pool: &ThreadPool, stream: &mut TcpStream, data: &mut [u8; LEGACY_REQUEST_SIZE]) -> Result<(), ServerError> {
+	
+	// a legacy connection is a single request, so its client has a single search
+	let client = TableClient::take_slot();
+	
+	// read the rest of the 20 bytes from client
+	// a client that stops after a partial request may still be waiting for a reply
+	let reply = match stream.read_exact(&mut data[8..]) {
+		Ok(()) => {
+			let data = *data;
+			run_on_worker(engine, pool, move |engine| server_handle_request(engine, client, &data, None, false, None))
+		}
+		Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(ServerError::Malformed("short request".to_string())),
+		Err(e) => return Err(e.into())
+	};
+	
+	// return best move or the error to client
+	match reply {
+		Ok(reply) => stream.write_all(&reply)?,
+		Err(e) => {
+			if let Some(code) = e.code() {
+				stream.write_all(&encode_legacy_error(code))?;
+			}
+			stream.flush()?;
+			return Err(e);
+		}
+	}
 	
-	println!("Starting server...");
+	stream.flush()?;
 	
-	// load opening book
-	// TODO: since we only read from the book, a lock isn't needed, although may be good to add RwLock anyway
-	let book = Arc::new(read_book("data/book.dat"));
-	println!("Loaded {} positions into book", book.len());
+	Ok(())
 	
-	// load pytorch model
-	let mut model = tch::CModule::load("data/model.pt")
-		.unwrap();
+}
+
+/// Handle a v2 client, whose magic has already been read
+/// Serves framed requests until the client closes the connection
+/// Requests that can't be handled get a MSG_ERROR reply and the connection stays open
+fn server_handle_v2(engine: &Arc<Engine>, pool: &ThreadPool, stream: &mut TcpStream, idle_timeout: Option<Duration>) -> Result<(), ServerError> {
+	
+	let version = accept_handshake(stream)?;
+	
+	// the connection plays one game, whose searches reuse each other's table entries
+	let client = TableClient::take_slot();
+	
+	println!("Client connected with protocol v{}", version);
+	
+	// v3 labels each score with its kind, v4 adds the engine status to the replies
+	let with_kind = version >= 3;
+	let status = if version >= 4 { Some(engine.status.flags()) } else { None };
+	
+	loop {
+		
+		// a GUI can think for a long time between moves, so only the idle timeout applies until the next frame starts
+		stream.set_read_timeout(idle_timeout)?;
+		let waiting = stream.peek(&mut [0u8; 1]);
+		stream.set_read_timeout(Some(READ_TIMEOUT))?;
+		match waiting {
+			// the client hung up between requests
+			Ok(0) => return Ok(()),
+			Ok(_) => {}
+			Err(e) => return Err(e.into())
+		}
+		
+		let frame = read_frame(stream)?;
+		
+		let reply = match frame.msg_type {
+			MSG_SEARCH => match <&[u8; LEGACY_REQUEST_SIZE]>::try_from(frame.payload.as_slice()) {
+				Ok(&data) => {
+					run_on_worker(engine, pool, move |engine| server_handle_request(engine, client, &data, None, with_kind, status))
+						.map(|reply| (MSG_SEARCH_REPLY, reply))
+				}
+				Err(_) => Err(ServerError::Malformed(format!("search payload of {} bytes", frame.payload.len())))
+			},
+			MSG_SEARCH_TIMED => match frame.payload.len() {
+				TIMED_REQUEST_SIZE => {
+					let (data, move_time) = frame.payload.split_at(LEGACY_REQUEST_SIZE);
+					let data: [u8; LEGACY_REQUEST_SIZE] = data.try_into().unwrap();
+					let move_time = Duration::from_millis(u32::from_be_bytes(move_time.try_into().unwrap()) as u64);
+					run_on_worker(engine, pool, move |engine| server_handle_request(engine, client, &data, Some(move_time), with_kind, status))
+						.map(|reply| (MSG_SEARCH_REPLY, reply))
+				}
+				len => Err(ServerError::Malformed(format!("timed search payload of {} bytes", len)))
+			},
+			MSG_ANALYZE => match frame.payload.split_last() {
+				Some((&n, data)) if data.len() == LEGACY_REQUEST_SIZE => {
+					let data: [u8; LEGACY_REQUEST_SIZE] = data.try_into().unwrap();
+					run_on_worker(engine, pool, move |engine| server_handle_analysis(engine, client, &data, n, with_kind, status))
+						.map(|reply| (MSG_ANALYZE_REPLY, reply))
+				}
+				_ => Err(ServerError::Malformed(format!("analyze payload of {} bytes", frame.payload.len())))
+			},
+			MSG_PING => Ok((MSG_PONG, status.into_iter().collect())),
+			MSG_CLOSE => return Ok(()),
+			_ => Ok((MSG_ERROR, vec![ERR_UNKNOWN_MESSAGE]))
+		};
+		
+		match reply {
+			Ok((msg_type, reply)) => write_frame(stream, frame.request_id, msg_type, &reply)?,
+			Err(e) => match e.code() {
+				Some(code) => {
+					println!("Bad request from client: {}", e);
+					write_frame(stream, frame.request_id, MSG_ERROR, &[code])?;
+				}
+				None => return Err(e)
+			}
+		}
+		
+	}
+	
+}
+
+/// Loads the pytorch model and prepares it for inference
+#[cfg(feature = "torch")]
+#[allow(unused_mut)]
+pub fn load_model(path: &str) -> Result<CModule, tch::TchError> {
+	
+	let mut model = tch::CModule::load(path)?;
 	
 	// move to the GPU
 	#[cfg(feature = "gpu")] {
-		println!("Moving model to GPU...");
 		model.to(Device::Cuda(0), Kind::Float, false);
 	}
 	
 	model.set_eval();
 	
-	let model = Arc::new(Mutex::new(model));
+	Ok(model)
 	
-	// count network parameters for nice log message
-	let num_params = model.lock().unwrap().named_parameters().unwrap()
+}
+
+/// Counts the parameters of a model for nice log messages
+#[cfg(feature = "torch")]
+pub fn model_num_params(model: &CModule) -> i64 {
+	model.named_parameters().unwrap()
 		.iter()
 		.map(|(_, t)| t.size().into_iter().reduce(|a, b| a * b).unwrap())
 		.reduce(|a, b| a + b)
-		.unwrap();
-	println!("Loaded nn heuristic model with {} params", num_params);
+		.unwrap()
+}
+
+/// Sets up the midgame evaluator chosen in the config, only the neural network is loaded (see load_nn)
+/// Falls back to the classic evaluator if the network fails to load, which is recorded in status
+/// Returns the evaluator and a description for the log
+pub fn load_evaluator(config: &EngineConfig, replicas: usize, batched: bool, status: &mut EngineStatus) -> (MidgameEvaluator, String) {
+	match config.evaluator {
+		EvaluatorKind::NN => match load_nn(config, replicas, batched) {
+			Ok((nn, description)) => (MidgameEvaluator::NN(Box::new(nn)), description),
+			Err(e) => {
+				status.no_model = true;
+				(MidgameEvaluator::Classic, format!("classic evaluator, failed to load the neural network: {}", e))
+			}
+		},
+		EvaluatorKind::Pattern => (MidgameEvaluator::Pattern, "pattern evaluator".to_string()),
+		EvaluatorKind::DiskMobility => (MidgameEvaluator::DiskMobility, "disk and mobility evaluator".to_string()),
+		EvaluatorKind::Classic => (MidgameEvaluator::Classic, "classic evaluator".to_string()),
+		EvaluatorKind::Random => (MidgameEvaluator::Random(RandomEvaluator::new(config.eval_seed)), format!("random evaluator with seed {}", config.eval_seed))
+	}
+}
+
+/// Loads the neural network with the backend chosen in the config
+/// Midgame searches get `replicas` copies of a libtorch model, or share a batching service if `batched`
+/// Returns the provider and a description for the log, or why the network couldn't be loaded
+#[cfg_attr(not(feature = "torch"), allow(unused_variables))]
+pub fn load_nn(config: &EngineConfig, replicas: usize, batched: bool) -> Result<(NNProvider, String), String> {
+	
+	let batching = format!("batching up to {} boards within {}us", config.max_batch, config.batch_latency_us);
+	let max_latency = Duration::from_micros(config.batch_latency_us);
+	
+	match config.backend {
+		Backend::Native => {
+			let network = NativeNetwork::load(&config.weights_path)
+				.map_err(|e| format!("weights {}: {}", config.weights_path, e))?;
+			let description = format!("native nn heuristic with {} params and {} kernels", network.num_params(), network.kernel_name());
+			if batched {
+				Ok((NNProvider::Batched(InferenceService::start(Box::new(network), config.max_batch, max_latency)), format!("{}, {}", description, batching)))
+			} else {
+				Ok((NNProvider::Native(network), description))
+			}
+		}
+		Backend::Quantized => {
+			let network = NativeNetwork::load(&config.weights_path)
+				.map_err(|e| format!("weights {}: {}", config.weights_path, e))?;
+			let network = QuantizedNetwork::calibrate(&network, &random_game_positions(CALIBRATION_POSITIONS, CALIBRATION_SEED));
+			let description = format!("quantized nn heuristic with {} params and {} kernels", network.num_params(), network.kernel_name());
+			if batched {
+				Ok((NNProvider::Batched(InferenceService::start(Box::new(network), config.max_batch, max_latency)), format!("{}, {}", description, batching)))
+			} else {
+				Ok((NNProvider::Quantized(network), description))
+			}
+		}
+		#[cfg(feature = "torch")]
+		Backend::Torch => {
+			if batched {
+				let model = load_model(&config.model_path)
+					.map_err(|e| format!("model {}: {}", config.model_path, e))?;
+				let description = format!("nn heuristic model with {} params, {}", model_num_params(&model), batching);
+				Ok((NNProvider::Batched(InferenceService::start(Box::new(model), config.max_batch, max_latency)), description))
+			} else {
+				let models: Vec<CModule> = (0..replicas)
+					.map(|_| load_model(&config.model_path))
+					.collect::<Result<_, _>>()
+					.map_err(|e| format!("model {}: {}", config.model_path, e))?;
+				let params = model_num_params(&models[0]);
+				let models = ModelPool::new(models.into_iter().map(|model| Box::new(model) as Box<dyn NNBackend + Send>).collect());
+				let description = format!("{} nn heuristic models with {} params", models.size(), params);
+				Ok((NNProvider::Pool(models), description))
+			}
+		}
+	}
+	
+}
+
+/// Loads the opening book of the config, an empty book if there is none or it fails to load
+/// A failure is recorded in status
+/// Returns the book and a description for the log
+pub fn load_book(config: &EngineConfig, status: &mut EngineStatus) -> (OthelloBook, String) {
+	match &config.book_path {
+		Some(path) => match read_book(path) {
+			Ok(book) => {
+				let description = format!("Loaded {} positions into book", book.len());
+				(book, description)
+			}
+			Err(e) => {
+				status.no_book = true;
+				(OthelloBook::new(), format!("Failed to load the opening book {}: {}, running without one", path, e))
+			}
+		},
+		None => (OthelloBook::new(), "Running without an opening book".to_string())
+	}
+}
+
+/// Runs the server, only returns if the address can't be bound
+/// A model or book that fails to load doesn't stop the server, see EngineStatus
+pub fn server_start(config: &EngineConfig) -> std::io::Result<()> {
 	
-	// start listening on localhost:port
-	let listener = TcpListener::bind(format!("0.0.0.0:{}", port)).unwrap();
-	println!("Server listening on port {}", port);
+	println!("Starting server...");
+	println!("Generating moves with the {:?} kernel", BoardKernel::current());
 	
-	let pool = ThreadPool::new(8);
+	let mut status = EngineStatus::default();
+	
+	// load opening book
+	// TODO: since we only read from the book, a lock isn't needed, although may be good to add RwLock anyway
+	let (book, description) = load_book(config, &mut status);
+	println!("{}", description);
+	
+	// load the models, each midgame search uses its own copy or they share a batching service
+	let (evaluator, description) = load_evaluator(config, config.num_model_replicas(), config.batch_latency_us > 0, &mut status);
+	println!("Loaded {}", description);
+	#[cfg(feature = "gpu")] {
+		println!("Moved models to GPU");
+	}
+	
+	let mid_tt = TranspositionTable::with_size_mb(config.tt_mb);
+	let eg_tt = TranspositionTable::with_size_mb(config.eg_tt_mb);
+	println!("Allocated {}MB for the midgame and {}MB for the endgame transposition tables", mid_tt.size_bytes() / (1024 * 1024), eg_tt.size_bytes() / (1024 * 1024));
+	let eval_cache = EvalCache::with_size_mb(config.eval_cache_mb);
+	println!("Allocated {}MB for the eval cache", eval_cache.size_bytes() / (1024 * 1024));
+	
+	// the engine and its speed estimates are shared between all clients
+	let engine = Arc::new(Engine {
+		book,
+		evaluator,
+		time_manager: TimeManager::new(),
+		mid_tt,
+		eg_tt,
+		eval_cache,
+		sym_evals: config.sym_evals,
+		eg_threads: config.eg_threads,
+		eg_max_nodes: config.eg_max_nodes,
+		eg_max_time: config.eg_max_ms.map(Duration::from_millis),
+		eg_selective_empties: config.eg_selective_empties,
+		eg_confidence: config.eg_confidence,
+		limits: config.depth_limits,
+		status
+	});
+	
+	if status.degraded() {
+		println!("Running {}", status);
+	}
+	
+	// start listening on bind:port
+	let listener = TcpListener::bind((config.bind.as_str(), config.port))?;
+	println!("Server listening on {}:{}", config.bind, config.port);
+	
+	// searches run on the workers, each connection is read on its own thread
+	let pool = ThreadPool::new(config.workers);
+	let idle_timeout = config.idle_timeout.map(Duration::from_secs);
 	
 	for stream in listener.incoming() {
 		match stream {
 			Ok(stream) => {
-				let my_book = book.clone();
-				let my_model = model.clone();
-				pool.execute(move || {
-					server_handle_client(&my_book, &my_model, stream);
+				let my_engine = engine.clone();
+				let my_pool = pool.clone();
+				let spawned = thread::Builder::new().name("client".to_string()).spawn(move || {
+					if let Err(e) = server_handle_client(&my_engine, &my_pool, stream, idle_timeout) {
+						println!("Failed to serve client: {}", e);
+					}
 				});
+				if let Err(e) = spawned {
+					println!("Failed to start a thread for a client: {}", e);
+				}
 			}
 			Err(e) => {
 				println!("Error: {}", e);
@@ -261,4 +865,6 @@ pub fn server_start(port: u16) {
 	// close the socket server
 	drop(listener);
 	
+	Ok(())
+	
 }
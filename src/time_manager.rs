@@ -0,0 +1,158 @@
+use std::sync::Mutex;
+
+/// Effective branching factor of the midgame search per ply of (true) depth
+const MID_EBF: f64 = 3.0;
+
+/// Effective branching factor of an exact endgame solve per empty square
+const END_EBF: f64 = 2.6;
+
+/// A WLD solve takes roughly this fraction of the time of an exact solve
+const WLD_FRACTION: f64 = 0.4;
+
+/// Seconds always kept in reserve to cover network latency and move overhead
+const SAFETY_SECS: f64 = 1.0;
+
+/// Fraction of the remaining time always kept in reserve
+const SAFETY_FRACTION: f64 = 0.05;
+
+/// Max fraction of the usable time that can be spent on the endgame solve.
+/// Once solved, later solves are a fraction of the cost, so this can be large.
+const SOLVE_SHARE: f64 = 0.5;
+
+/// Number of empties before the midgame/endgame switch that get extra time
+const SWITCH_WINDOW: f64 = 6.0;
+
+/// Max multiplier for the time spent on a move right before the endgame switch
+const SWITCH_BONUS: f64 = 1.0;
+
+/// Weight of a new measurement in the speed estimates
+const EMA_WEIGHT: f64 = 0.3;
+
+/// Searches faster than this are dominated by overhead and aren't measured
+const MIN_SAMPLE_SECS: f64 = 0.01;
+
+/// Search settings picked by the time manager
+pub struct TimePlan {
+	/// Mid game (nn_search) search depth
+	pub mid_depth: u8,
+	/// Endgame search depth
+	pub end_depth: u8,
+	/// If true will solve exact endgame, If false solves WLD
+	pub solve_end_exact: bool
+}
+
+/// Measured speed of the engine
+/// Each unit is the time in seconds of a search scaled down by its branching factor
+struct SpeedEstimates {
+	/// est seconds for a midgame search of depth d = mid_unit * MID_EBF^d
+	mid_unit: f64,
+	/// est seconds for an exact solve of e empties = end_unit * END_EBF^e
+	end_unit: f64
+}
+
+/// Picks search depths that fit in the remaining game time.
+/// Speed estimates are shared between all clients and updated after every search.
+pub struct TimeManager {
+	speed: Mutex<SpeedEstimates>
+}
+
+impl TimeManager {
+	
+	/// Creates a time manager with initial speed estimates from the README benchmarks
+	/// (depth 6 midgame in ~0.9 sec, 20 empties exact in ~4 sec)
+	pub fn new() -> Self {
+		TimeManager {
+			speed: Mutex::new(SpeedEstimates {
+				mid_unit: 0.9 / MID_EBF.powi(6),
+				end_unit: 4.0 / END_EBF.powi(20)
+			})
+		}
+	}
+	
+	/// Estimated seconds to perform a midgame search with a true depth of `depth`
+	fn est_mid_secs(&self, depth: u8) -> f64 {
+		self.speed.lock().unwrap().mid_unit * MID_EBF.powi(depth as i32)
+	}
+	
+	/// Estimated seconds to solve a position with `empties` empty squares
+	fn est_end_secs(&self, empties: u8, exact: bool) -> f64 {
+		let secs = self.speed.lock().unwrap().end_unit * END_EBF.powi(empties as i32);
+		if exact { secs } else { secs * WLD_FRACTION }
+	}
+	
+	/// Records the time taken by a midgame search with a true depth of `depth`
+	pub fn record_mid(&self, depth: u8, secs: f64) {
+		if secs < MIN_SAMPLE_SECS {
+			return;
+		}
+		let sample = secs / MID_EBF.powi(depth as i32);
+		let mut speed = self.speed.lock().unwrap();
+		speed.mid_unit += EMA_WEIGHT * (sample - speed.mid_unit);
+	}
+	
+	/// Records the time taken by an endgame solve of `empties` empty squares
+	pub fn record_end(&self, empties: u8, exact: bool, secs: f64) {
+		if secs < MIN_SAMPLE_SECS {
+			return;
+		}
+		let secs = if exact { secs } else { secs / WLD_FRACTION };
+		let sample = secs / END_EBF.powi(empties as i32);
+		let mut speed = self.speed.lock().unwrap();
+		speed.end_unit += EMA_WEIGHT * (sample - speed.end_unit);
+	}
+	
+	/// Plans the search for a position with `empties` empty squares,
+	/// given `remaining_tenths` of a second left on our clock.
+	/// The depths and exact bit in `requested` are treated as upper limits.
+	pub fn plan(&self, requested: &TimePlan, remaining_tenths: u16, empties: u8) -> TimePlan {
+		
+		let remaining = remaining_tenths as f64 / 10.0;
+		let usable = ((remaining - SAFETY_SECS) * (1.0 - SAFETY_FRACTION)).max(0.0);
+		let solve_budget = usable * SOLVE_SHARE;
+		
+		// find the deepest endgame we can afford to solve, at least WLD
+		let mut switch_empties = 0;
+		for e in (1..=requested.end_depth).rev() {
+			if self.est_end_secs(e, false) <= solve_budget {
+				switch_empties = e;
+				break;
+			}
+		}
+		
+		// if this position can be solved, decide between exact and WLD
+		if empties <= switch_empties {
+			let exact = requested.solve_end_exact && self.est_end_secs(empties, true) <= solve_budget;
+			return TimePlan {
+				mid_depth: requested.mid_depth,
+				end_depth: empties,
+				solve_end_exact: exact
+			};
+		}
+		
+		// split the time left before the solve between our remaining midgame moves
+		let mid_time = (usable - self.est_end_secs(switch_empties, false)).max(0.0);
+		let mid_moves = ((empties - switch_empties) as f64 / 2.0).ceil().max(1.0);
+		
+		// spend more time right before the switch, where mistakes can't be fixed by the solver
+		let to_switch = (empties - switch_empties) as f64;
+		let bonus = 1.0 + SWITCH_BONUS * ((SWITCH_WINDOW - to_switch) / SWITCH_WINDOW).max(0.0);
+		let mid_budget = bonus * mid_time / mid_moves;
+		
+		// find the deepest midgame search that fits in the budget
+		let mut mid_depth = 1;
+		for d in (1..=requested.mid_depth).rev() {
+			if self.est_mid_secs(d) <= mid_budget {
+				mid_depth = d;
+				break;
+			}
+		}
+		
+		TimePlan {
+			mid_depth,
+			end_depth: switch_empties,
+			solve_end_exact: requested.solve_end_exact
+		}
+		
+	}
+	
+}
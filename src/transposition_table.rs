@@ -0,0 +1,430 @@
+use std::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+
+/// Size of a slot in bytes
+const SLOT_SIZE: usize = 16;
+
+/// Bits of the data word used by the payload, the rest holds the depth, client and generation
+const PAYLOAD_BITS: u32 = 42;
+const PAYLOAD_MASK: u64 = (1 << PAYLOAD_BITS) - 1;
+
+/// Positions of the depth (8 bits), client slot (6 bits) and generation (8 bits) in the data word
+const DEPTH_SHIFT: u32 = PAYLOAD_BITS;
+const CLIENT_SHIFT: u32 = 50;
+const GENERATION_SHIFT: u32 = 56;
+
+/// Number of clients with a generation of their own, further clients share them
+pub const CLIENT_SLOTS: usize = 64;
+
+/// What a stored score says about the true score
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Bound {
+	/// the true score
+	Exact,
+	/// the true score is >= the stored score (failed high)
+	Lower,
+	/// the true score is <= the stored score (failed low)
+	Upper
+}
+
+impl Bound {
+	
+	/// Bound of a fail-soft score searched with the window (alpha, beta)
+	pub fn from_window(score: i32, alpha: i32, beta: i32) -> Self {
+		if score <= alpha {
+			Bound::Upper
+		} else if score >= beta {
+			Bound::Lower
+		} else {
+			Bound::Exact
+		}
+	}
+	
+	fn to_bits(self) -> u64 {
+		match self {
+			Bound::Exact => 0,
+			Bound::Lower => 1,
+			Bound::Upper => 2
+		}
+	}
+	
+	fn from_bits(bits: u64) -> Self {
+		match bits {
+			0 => Bound::Exact,
+			1 => Bound::Lower,
+			_ => Bound::Upper
+		}
+	}
+	
+}
+
+/// A midgame search result stored in the table
+#[derive(Clone, Copy, Debug)]
+pub struct MidEntry {
+	/// centidisk score from the POV of the player to move
+	pub score: i32,
+	pub bound: Bound,
+	/// best move found, 65 for a pass, 64 if there is no move (leaf nodes)
+	pub best_move: u8
+}
+
+impl MidEntry {
+	
+	/// score: 32 bits, bound: 2 bits, best_move: 7 bits
+	fn pack(&self) -> u64 {
+		(self.score as u32 as u64) | (self.bound.to_bits() << 32) | ((self.best_move as u64) << 34)
+	}
+	
+	fn unpack(payload: u64) -> Self {
+		MidEntry {
+			score: payload as u32 as i32,
+			bound: Bound::from_bits((payload >> 32) & 0b11),
+			best_move: ((payload >> 34) & 0x7F) as u8
+		}
+	}
+	
+	/// Returns the stored score if it decides the node for the window (alpha, beta)
+	pub fn cutoff(&self, alpha: i32, beta: i32) -> Option<i32> {
+		match self.bound {
+			Bound::Exact => Some(self.score),
+			Bound::Lower if self.score >= beta => Some(self.score),
+			Bound::Upper if self.score <= alpha => Some(self.score),
+			_ => None
+		}
+	}
+	
+}
+
+/// An endgame solver result stored in the table
+/// The true disk difference is within lower..=upper
+#[derive(Clone, Copy, Debug)]
+pub struct EgEntry {
+	pub lower: i8,
+	pub upper: i8,
+	/// best move found, 65 for a pass
+	pub best_move: u8,
+	/// EG_PROVEN if the bounds are proven, otherwise the Confidence of the selective solve that found them
+	pub confidence: u8
+}
+
+/// Confidence of the bounds of an exact solve, above every selective level
+pub const EG_PROVEN: u8 = 7;
+
+impl EgEntry {
+	
+	/// Bounds of a fail-soft score searched with the window (alpha, beta)
+	pub fn from_window(score: i8, alpha: i8, beta: i8, best_move: u8) -> Self {
+		EgEntry {
+			lower: if score > alpha { score } else { -64 },
+			upper: if score < beta { score } else { 64 },
+			best_move,
+			confidence: EG_PROVEN
+		}
+	}
+	
+	/// lower: 8 bits, upper: 8 bits, best_move: 7 bits, confidence: 3 bits
+	fn pack(&self) -> u64 {
+		(self.lower as u8 as u64) | ((self.upper as u8 as u64) << 8) | ((self.best_move as u64) << 16) | ((self.confidence as u64) << 23)
+	}
+	
+	fn unpack(payload: u64) -> Self {
+		EgEntry {
+			lower: payload as u8 as i8,
+			upper: (payload >> 8) as u8 as i8,
+			best_move: ((payload >> 16) & 0x7F) as u8,
+			confidence: ((payload >> 23) & 0b111) as u8
+		}
+	}
+	
+}
+
+/// A single entry, the key is stored xor the data so a torn write from another thread
+/// is seen as a miss instead of a wrong entry
+struct Slot {
+	key: AtomicU64,
+	data: AtomicU64
+}
+
+/// Lockless hash table of search results, shared by all searches
+/// Each slot holds a single position. An entry from an older search (generation) of its client
+/// is always replaced, otherwise an entry is only replaced by a search of at least the same depth.
+/// Every client counts its own generations, so the searches of one client don't age the entries of another.
+pub struct TranspositionTable {
+	slots: Box<[Slot]>,
+	mask: usize,
+	/// generation of the current search of each client slot
+	generations: [AtomicU8; CLIENT_SLOTS]
+}
+
+/// A client of the tables, like a connection or an NBoard game
+/// Clients take turns on the CLIENT_SLOTS slots, clients that share a slot age each other's entries
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TableClient(u8);
+
+/// Slot of the next client
+static NEXT_CLIENT: AtomicUsize = AtomicUsize::new(0);
+
+impl TableClient {
+	
+	/// Takes the next client slot
+	pub fn take_slot() -> Self {
+		TableClient((NEXT_CLIENT.fetch_add(1, Ordering::Relaxed) % CLIENT_SLOTS) as u8)
+	}
+	
+}
+
+/// Hashes a position, the player to move is implied by the order of the bitboards
+#[inline(always)]
+pub fn hash_position(me: u64, enemy: u64) -> u64 {
+	// mix both boards, then the murmur3 finalizer
+	let mut h = me.wrapping_mul(0x9E3779B97F4A7C15) ^ enemy.rotate_left(29).wrapping_mul(0xD6E8FEB86659FD93);
+	h ^= h >> 33;
+	h = h.wrapping_mul(0xFF51AFD7ED558CCD);
+	h ^= h >> 33;
+	h = h.wrapping_mul(0xC4CEB9FE1A85EC53);
+	h ^= h >> 33;
+	h
+}
+
+impl TranspositionTable {
+	
+	/// Creates a table that uses at most `mb` megabytes, rounded down to a power of 2 slots
+	pub fn with_size_mb(mb: usize) -> Self {
+		
+		let max_slots = (mb * 1024 * 1024 / SLOT_SIZE).max(1);
+		let num_slots = 1 << (usize::BITS - 1 - max_slots.leading_zeros());
+		
+		let slots = (0..num_slots)
+			.map(|_| Slot { key: AtomicU64::new(0), data: AtomicU64::new(0) })
+			.collect();
+		
+		TranspositionTable {
+			slots,
+			mask: num_slots - 1,
+			generations: std::array::from_fn(|_| AtomicU8::new(1))
+		}
+		
+	}
+	
+	/// Size of the table in bytes
+	pub fn size_bytes(&self) -> usize {
+		self.slots.len() * SLOT_SIZE
+	}
+	
+	/// The table as used by the searches of `client`
+	pub fn for_client(&self, client: TableClient) -> ClientTable<'_> {
+		ClientTable { table: self, client }
+	}
+	
+	/// Starts a new search of `client`, entries of its previous searches are kept but are replaced first
+	fn new_search(&self, client: TableClient) {
+		self.generations[client.0 as usize].fetch_add(1, Ordering::Relaxed);
+	}
+	
+	/// Generation of the current search of a client slot, 0 is left for empty slots
+	#[inline(always)]
+	fn generation(&self, slot: usize) -> u8 {
+		self.generations[slot].load(Ordering::Relaxed).max(1)
+	}
+	
+	/// Looks up a position, returns the (depth, payload) stored for it
+	#[inline(always)]
+	fn probe(&self, hash: u64) -> Option<(u8, u64)> {
+		
+		let slot = &self.slots[hash as usize & self.mask];
+		let data = slot.data.load(Ordering::Relaxed);
+		
+		// an empty slot has data = 0 (generation 0), which no search uses
+		if data == 0 || slot.key.load(Ordering::Relaxed) ^ data != hash {
+			return None;
+		}
+		
+		Some(((data >> DEPTH_SHIFT) as u8, data & PAYLOAD_MASK))
+		
+	}
+	
+	/// Stores the payload for a position searched to `depth` by `client`
+	#[inline(always)]
+	fn store(&self, client: TableClient, hash: u64, depth: u8, payload: u64) {
+		
+		let slot = &self.slots[hash as usize & self.mask];
+		let generation = self.generation(client.0 as usize);
+		
+		// keep a deeper result of the current search of any client for another position
+		let old = slot.data.load(Ordering::Relaxed);
+		let old_generation = (old >> GENERATION_SHIFT) as u8;
+		let old_client = ((old >> CLIENT_SHIFT) as usize) % CLIENT_SLOTS;
+		let old_depth = (old >> DEPTH_SHIFT) as u8;
+		let same_position = slot.key.load(Ordering::Relaxed) ^ old == hash;
+		if old_generation == self.generation(old_client) && old_depth > depth && !same_position {
+			return;
+		}
+		
+		let data = ((generation as u64) << GENERATION_SHIFT) | ((client.0 as u64) << CLIENT_SHIFT) | ((depth as u64) << DEPTH_SHIFT) | (payload & PAYLOAD_MASK);
+		slot.key.store(hash ^ data, Ordering::Relaxed);
+		slot.data.store(data, Ordering::Relaxed);
+		
+	}
+	
+	/// Looks up a midgame entry searched to at least `depth`
+	/// The best move is returned for shallower entries, to order moves
+	#[inline(always)]
+	pub fn probe_mid(&self, hash: u64, depth: i8) -> (Option<MidEntry>, u8) {
+		match self.probe(hash) {
+			Some((entry_depth, payload)) => {
+				let entry = MidEntry::unpack(payload);
+				let usable = if entry_depth as i8 >= depth { Some(entry) } else { None };
+				(usable, entry.best_move)
+			}
+			None => (None, 64)
+		}
+	}
+	
+	/// Stores a midgame entry searched to `depth`
+	#[inline(always)]
+	fn store_mid(&self, client: TableClient, hash: u64, depth: i8, entry: MidEntry) {
+		self.store(client, hash, depth.max(0) as u8, entry.pack());
+	}
+	
+	/// Looks up an endgame entry, every entry of a position is solved to the end of the game
+	#[inline(always)]
+	pub fn probe_eg(&self, hash: u64) -> Option<EgEntry> {
+		self.probe(hash).map(|(_, payload)| EgEntry::unpack(payload))
+	}
+	
+	/// Stores an endgame entry, positions with more empties are kept over ones with fewer
+	/// The bounds are combined with an entry of the same confidence already stored for the position,
+	/// and never replace the bounds of a more confident entry
+	#[inline(always)]
+	fn store_eg(&self, client: TableClient, hash: u64, empties: u8, mut entry: EgEntry) {
+		if let Some(old) = self.probe_eg(hash) {
+			if old.confidence > entry.confidence {
+				return;
+			}
+			// selective bounds can cross, the new entry then replaces the old one
+			let (lower, upper) = (entry.lower.max(old.lower), entry.upper.min(old.upper));
+			if old.confidence == entry.confidence && lower <= upper {
+				entry.lower = lower;
+				entry.upper = upper;
+			}
+		}
+		self.store(client, hash, empties, entry.pack());
+	}
+	
+}
+
+/// A table used by the searches of one client, see TableClient
+#[derive(Clone, Copy)]
+pub struct ClientTable<'a> {
+	table: &'a TranspositionTable,
+	client: TableClient
+}
+
+impl ClientTable<'_> {
+	
+	/// Starts a new search of the client, see TranspositionTable::new_search
+	pub fn new_search(&self) {
+		self.table.new_search(self.client);
+	}
+	
+	#[inline(always)]
+	pub fn probe_mid(&self, hash: u64, depth: i8) -> (Option<MidEntry>, u8) {
+		self.table.probe_mid(hash, depth)
+	}
+	
+	#[inline(always)]
+	pub fn store_mid(&self, hash: u64, depth: i8, entry: MidEntry) {
+		self.table.store_mid(self.client, hash, depth, entry);
+	}
+	
+	#[inline(always)]
+	pub fn probe_eg(&self, hash: u64) -> Option<EgEntry> {
+		self.table.probe_eg(hash)
+	}
+	
+	#[inline(always)]
+	pub fn store_eg(&self, hash: u64, empties: u8, entry: EgEntry) {
+		self.table.store_eg(self.client, hash, empties, entry);
+	}
+	
+}
+
+#[cfg(test)]
+mod tests {
+	
+	use super::*;
+	
+	fn entry(score: i32) -> MidEntry {
+		MidEntry { score, bound: Bound::Exact, best_move: 19 }
+	}
+	
+	/// Score stored for a position, whatever its depth
+	fn stored_score(tt: &TranspositionTable, hash: u64) -> Option<i32> {
+		tt.probe_mid(hash, 0).0.map(|entry| entry.score)
+	}
+	
+	#[test]
+	fn new_search_ages_own_entries() {
+		
+		// a single slot, so every position competes for it
+		let tt = TranspositionTable::with_size_mb(0);
+		let client = tt.for_client(TableClient(1));
+		
+		client.new_search();
+		client.store_mid(1, 6, entry(100));
+		client.store_mid(2, 2, entry(200));
+		assert_eq!(stored_score(&tt, 1), Some(100));
+		
+		client.new_search();
+		client.store_mid(2, 2, entry(200));
+		assert_eq!(stored_score(&tt, 2), Some(200));
+		assert_eq!(stored_score(&tt, 1), None);
+		
+	}
+	
+	#[test]
+	fn interleaved_clients_keep_their_entries() {
+		
+		let tt = TranspositionTable::with_size_mb(0);
+		let (a, b) = (tt.for_client(TableClient(1)), tt.for_client(TableClient(2)));
+		
+		a.new_search();
+		a.store_mid(1, 6, entry(100));
+		
+		// b starts a search while a's is running, a's deeper entry is kept
+		b.new_search();
+		b.store_mid(2, 2, entry(200));
+		assert_eq!(stored_score(&tt, 1), Some(100));
+		assert_eq!(stored_score(&tt, 2), None);
+		
+		// once a starts its next search, its old entry can be replaced by b
+		a.new_search();
+		b.store_mid(2, 2, entry(200));
+		assert_eq!(stored_score(&tt, 2), Some(200));
+		
+		// a's new entries are again kept over b's shallower ones, across b's next search too
+		a.store_mid(3, 4, entry(300));
+		b.new_search();
+		b.store_mid(2, 1, entry(200));
+		assert_eq!(stored_score(&tt, 3), Some(300));
+		
+	}
+	
+	#[test]
+	fn crossing_selective_bounds_are_replaced() {
+		
+		let tt = TranspositionTable::with_size_mb(1);
+		let client = tt.for_client(TableClient(1));
+		let selective = |lower, upper| EgEntry { lower, upper, best_move: 19, confidence: 2 };
+		
+		client.store_eg(1, 20, selective(-64, 10));
+		client.store_eg(1, 20, selective(4, 64));
+		let merged = client.probe_eg(1).unwrap();
+		assert_eq!((merged.lower, merged.upper), (4, 10));
+		
+		client.store_eg(1, 20, selective(-64, 2));
+		let replaced = client.probe_eg(1).unwrap();
+		assert_eq!((replaced.lower, replaced.upper), (-64, 2));
+		
+	}
+	
+}
@@ -0,0 +1,105 @@
+"""
+Exports the weights of a trained network for the native backend (--backend native --weights <out>)
+Usage: python export_weights.py <othello_model.pt or chpt_n.pth> <out.bin>
+The file also holds the evals of the model on random boards, the engine checks it reproduces them when loading.
+"""
+
+import re
+import struct
+import sys
+
+import numpy as np
+import torch
+
+# must match WEIGHTS_MAGIC in src/native_network.rs
+WEIGHTS_MAGIC = b"OTHNNW01"
+NUM_SAMPLES = 256
+
+
+def load_model(path):
+    """
+    Loads a torchscript model, or a training checkpoint into OthelloNNet
+    """
+    if path.endswith(".pth"):
+        from train_network import OthelloNNet
+        state_dict = torch.load(path, map_location="cpu")['model_state_dict']
+        hidden = state_dict['seq.0.weight'].shape[0]
+        model = OthelloNNet(hidden)
+        model.load_state_dict(state_dict)
+    else:
+        model = torch.jit.load(path, map_location="cpu")
+    model.eval()
+    return model
+
+
+def linear_layers(state_dict):
+    """
+    Returns the (weight, bias) of every Linear layer in forward order
+    """
+    layers = []
+    for key in state_dict:
+        if key.endswith(".weight"):
+            prefix = key[:-len(".weight")]
+            layers.append((prefix, state_dict[key], state_dict[prefix + ".bias"]))
+    
+    # seq.<i>[.l1/.l2], sorted by the position in the Sequential
+    def order(layer):
+        m = re.match(r"seq\.(\d+)(?:\.l(\d))?$", layer[0])
+        return int(m.group(1)), int(m.group(2) or 0)
+    
+    return [(w, b) for _, w, b in sorted(layers, key=order)]
+
+
+def random_boards(n, rng):
+    """
+    Random (me, enemy) bitboards with every amount of disks
+    """
+    boards = []
+    for i in range(n):
+        squares = rng.permutation(64)
+        disks = rng.integers(4, 65)
+        me_count = rng.integers(0, disks + 1)
+        me = sum(1 << int(sq) for sq in squares[:me_count])
+        enemy = sum(1 << int(sq) for sq in squares[me_count:disks])
+        boards.append((me, enemy))
+    return boards
+
+
+def board_to_vec(me, enemy):
+    return [1.0 if me >> i & 1 else -1.0 if enemy >> i & 1 else 0.0 for i in range(64)]
+
+
+def main():
+    
+    model_path, out_path = sys.argv[1], sys.argv[2]
+    
+    model = load_model(model_path)
+    layers = linear_layers(model.state_dict())
+    
+    # input, 2 per block, head, output
+    hidden = layers[0][0].shape[0]
+    head = layers[-1][0].shape[1]
+    blocks = (len(layers) - 3) // 2
+    print(f"{hidden=} {blocks=} {head=}")
+    
+    rng = np.random.default_rng(0)
+    boards = random_boards(NUM_SAMPLES, rng)
+    with torch.no_grad():
+        x = torch.tensor([board_to_vec(me, enemy) for me, enemy in boards], dtype=torch.float32)
+        evals = model(x).reshape(-1).numpy()
+    
+    with open(out_path, "wb") as f:
+        f.write(WEIGHTS_MAGIC)
+        f.write(struct.pack("<IIII", 64, hidden, blocks, head))
+        for w, b in layers:
+            f.write(w.detach().numpy().astype("<f4").tobytes())
+            f.write(b.detach().numpy().astype("<f4").tobytes())
+        f.write(struct.pack("<I", len(boards)))
+        for (me, enemy), q in zip(boards, evals):
+            f.write(struct.pack("<QQf", me, enemy, q))
+    
+    print(f"Wrote {sum(w.numel() + b.numel() for w, b in layers)} params and {len(boards)} samples to {out_path}")
+
+
+if __name__ == '__main__':
+    main()